@@ -0,0 +1,176 @@
+/*
+ * The per-project defaults, from a .tbpatch.toml checked in with the code:
+ *
+ *   strip = 1
+ *   ws = "infer"
+ *   ws_only_edits = false
+ *   min_similarity = 0.0
+ *   backup = false
+ *   merge = "diff3"
+ *
+ *   [[tokenizer]]
+ *   glob = "*.md"
+ *   name = "words"
+ */
+use crate::parse::Tokenizer;
+use crate::patch::{MergeStyle, PatchOptions, WsPolicy};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE_NAME: &str = ".tbpatch.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Parse { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    strip: Option<usize>,
+    ws: Option<String>,
+    ws_only_edits: Option<bool>,
+    min_similarity: Option<f64>,
+    backup: Option<bool>,
+    merge: Option<String>,
+    #[serde(default)]
+    tokenizer: Vec<TokenizerRule>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenizerRule {
+    glob: String,
+    name: String,
+}
+
+/// The settings from the config file, None for the ones it does not set
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub strip: Option<usize>,
+    pub ws: Option<WsPolicy>,
+    pub ws_only_edits: Option<bool>,
+    pub min_similarity: Option<f64>,
+    pub backup: Option<bool>,
+    pub merge: Option<MergeStyle>,
+    /// the first one whose pattern matches the path of a file is used for it
+    pub tokenizers: Vec<(glob::Pattern, Tokenizer)>,
+}
+
+impl Config {
+    pub fn parse(text: &str, path: &Path) -> Result<Config, ConfigError> {
+        let parse_err = |error: String| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error,
+        };
+        let file: ConfigFile = toml::from_str(text).map_err(|e| parse_err(e.to_string()))?;
+        let mut tokenizers = vec![];
+        for rule in &file.tokenizer {
+            let pattern = glob::Pattern::new(&rule.glob)
+                .map_err(|e| parse_err(format!("glob '{}': {}", rule.glob, e)))?;
+            tokenizers.push((pattern, rule.name.parse().map_err(parse_err)?));
+        }
+        Ok(Config {
+            strip: file.strip,
+            ws: file
+                .ws
+                .map(|ws| ws.parse())
+                .transpose()
+                .map_err(parse_err)?,
+            ws_only_edits: file.ws_only_edits,
+            min_similarity: file.min_similarity,
+            backup: file.backup,
+            merge: file
+                .merge
+                .map(|merge| merge.parse())
+                .transpose()
+                .map_err(parse_err)?,
+            tokenizers,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Config::parse(&text, path)
+    }
+
+    /// The config file in the directory or the closest one above it
+    pub fn discover(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file())
+    }
+
+    /// The options with the settings of the config file in place of the defaults
+    pub fn options(&self) -> PatchOptions {
+        let defaults = PatchOptions::default();
+        PatchOptions {
+            strip: self.strip.unwrap_or(defaults.strip),
+            ws: self.ws.unwrap_or(defaults.ws),
+            ws_only_edits: self.ws_only_edits.unwrap_or(defaults.ws_only_edits),
+            min_similarity: self.min_similarity.unwrap_or(defaults.min_similarity),
+            backup: self.backup.unwrap_or(defaults.backup),
+            merge: self.merge.or(defaults.merge),
+            ..defaults
+        }
+    }
+
+    pub fn tokenizer_for(&self, path: &str) -> Option<Tokenizer> {
+        self.tokenizers
+            .iter()
+            .find(|(pattern, _)| pattern.matches(path))
+            .map(|(_, tokenizer)| *tokenizer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let text =
+            "strip = 0\nws = \"keep-target\"\n\n[[tokenizer]]\nglob = \"*.md\"\nname = \"words\"\n";
+        let config = Config::parse(text, Path::new(CONFIG_FILE_NAME)).unwrap();
+        let options = config.options();
+        assert_eq!(options.strip, 0);
+        assert_eq!(options.ws, WsPolicy::KeepTarget);
+        assert!(!options.backup);
+        assert_eq!(
+            config.tokenizer_for("doc/README.md"),
+            Some(Tokenizer::Words)
+        );
+        assert_eq!(config.tokenizer_for("src/main.c"), None);
+    }
+
+    #[test]
+    fn parse_config_errors() {
+        let path = Path::new(CONFIG_FILE_NAME);
+        assert!(Config::parse("fuzz = 2\n", path).is_err());
+        assert!(Config::parse("ws = \"tabs\"\n", path).is_err());
+        assert!(Config::parse("merge = \"yes\"\n", path).is_err());
+        assert!(Config::parse("[[tokenizer]]\nglob = \"*\"\nname = \"lisp\"\n", path).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+/*
+ * Where the files being patched come from and go to.
+ */
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub trait FileSystem {
+    fn read_to_string(&self, path: &str) -> io::Result<String>;
+    /// Create or overwrite the file, along with the directories leading to it
+    fn write(&self, path: &str, contents: &str) -> io::Result<()>;
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    fn delete(&self, path: &str) -> io::Result<()>;
+    /// Set the permission bits, e.g. 0o755
+    fn set_mode(&self, path: &str, mode: u32) -> io::Result<()>;
+    fn exists(&self, path: &str) -> bool;
+}
+
+/// The files on the disk, with the paths relative to the current directory
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskFileSystem;
+
+fn create_parent_dir(path: &str) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+impl FileSystem for DiskFileSystem {
+    fn read_to_string(&self, path: &str) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn write(&self, path: &str, contents: &str) -> io::Result<()> {
+        create_parent_dir(path)?;
+        std::fs::write(path, contents)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        create_parent_dir(to)?;
+        std::fs::rename(from, to)
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    #[cfg(unix)]
+    fn set_mode(&self, path: &str, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    fn set_mode(&self, _path: &str, _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        Path::new(path).exists()
+    }
+}
+
+/// The files under a directory, with the paths relative to it
+#[derive(Clone, Debug)]
+pub struct DirFileSystem {
+    root: PathBuf,
+}
+
+impl DirFileSystem {
+    pub fn new(root: &Path) -> Self {
+        DirFileSystem {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, path: &str) -> String {
+        self.root.join(path).to_string_lossy().into_owned()
+    }
+}
+
+impl FileSystem for DirFileSystem {
+    fn read_to_string(&self, path: &str) -> io::Result<String> {
+        DiskFileSystem.read_to_string(&self.path(path))
+    }
+
+    fn write(&self, path: &str, contents: &str) -> io::Result<()> {
+        DiskFileSystem.write(&self.path(path), contents)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        DiskFileSystem.rename(&self.path(from), &self.path(to))
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        DiskFileSystem.delete(&self.path(path))
+    }
+
+    fn set_mode(&self, path: &str, mode: u32) -> io::Result<()> {
+        DiskFileSystem.set_mode(&self.path(path), mode)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        DiskFileSystem.exists(&self.path(path))
+    }
+}
+
+/// A file of the MemoryFileSystem
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryFile {
+    pub contents: String,
+    pub mode: u32,
+}
+
+/// A tree of files held in memory, keyed by their paths
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFileSystem {
+    files: RefCell<BTreeMap<String, MemoryFile>>,
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file", path))
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a file with the default mode
+    pub fn insert(&self, path: &str, contents: &str) {
+        self.files.borrow_mut().insert(
+            path.to_string(),
+            MemoryFile {
+                contents: contents.to_string(),
+                mode: 0o644,
+            },
+        );
+    }
+
+    pub fn get(&self, path: &str) -> Option<MemoryFile> {
+        self.files.borrow().get(path).cloned()
+    }
+
+    pub fn files(&self) -> BTreeMap<String, MemoryFile> {
+        self.files.borrow().clone()
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read_to_string(&self, path: &str) -> io::Result<String> {
+        self.get(path)
+            .map(|file| file.contents)
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn write(&self, path: &str, contents: &str) -> io::Result<()> {
+        let mut files = self.files.borrow_mut();
+        match files.get_mut(path) {
+            Some(file) => file.contents = contents.to_string(),
+            None => {
+                files.insert(
+                    path.to_string(),
+                    MemoryFile {
+                        contents: contents.to_string(),
+                        mode: 0o644,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.borrow_mut();
+        let file = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_string(), file);
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        self.files
+            .borrow_mut()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn set_mode(&self, path: &str, mode: u32) -> io::Result<()> {
+        let mut files = self.files.borrow_mut();
+        let file = files.get_mut(path).ok_or_else(|| not_found(path))?;
+        file.mode = mode;
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.files.borrow().contains_key(path)
+    }
+}
+
+/// Keeps the changes in memory, reading the files not changed from another FileSystem
+pub struct OverlayFileSystem<'a> {
+    base: &'a dyn FileSystem,
+    /// None for the files which have been deleted
+    changes: RefCell<BTreeMap<String, Option<MemoryFile>>>,
+}
+
+impl<'a> OverlayFileSystem<'a> {
+    pub fn new(base: &'a dyn FileSystem) -> Self {
+        OverlayFileSystem {
+            base,
+            changes: Default::default(),
+        }
+    }
+
+    /// The files written or deleted so far
+    pub fn changes(&self) -> BTreeMap<String, Option<MemoryFile>> {
+        self.changes.borrow().clone()
+    }
+
+    fn changed_file(&self, path: &str) -> io::Result<MemoryFile> {
+        match self.changes.borrow().get(path) {
+            Some(Some(file)) => return Ok(file.clone()),
+            Some(None) => return Err(not_found(path)),
+            None => {}
+        }
+        Ok(MemoryFile {
+            contents: self.base.read_to_string(path)?,
+            mode: 0o644,
+        })
+    }
+}
+
+impl<'a> FileSystem for OverlayFileSystem<'a> {
+    fn read_to_string(&self, path: &str) -> io::Result<String> {
+        self.changed_file(path).map(|file| file.contents)
+    }
+
+    fn write(&self, path: &str, contents: &str) -> io::Result<()> {
+        let mode = self.changed_file(path).map_or(0o644, |file| file.mode);
+        self.changes.borrow_mut().insert(
+            path.to_string(),
+            Some(MemoryFile {
+                contents: contents.to_string(),
+                mode,
+            }),
+        );
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let file = self.changed_file(from)?;
+        let mut changes = self.changes.borrow_mut();
+        changes.insert(from.to_string(), None);
+        changes.insert(to.to_string(), Some(file));
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        if !self.exists(path) {
+            return Err(not_found(path));
+        }
+        self.changes.borrow_mut().insert(path.to_string(), None);
+        Ok(())
+    }
+
+    fn set_mode(&self, path: &str, mode: u32) -> io::Result<()> {
+        let file = self.changed_file(path)?;
+        self.changes
+            .borrow_mut()
+            .insert(path.to_string(), Some(MemoryFile { mode, ..file }));
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        match self.changes.borrow().get(path) {
+            Some(file) => file.is_some(),
+            None => self.base.exists(path),
+        }
+    }
+}
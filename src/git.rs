@@ -0,0 +1,510 @@
+/*
+ * Patching the files in the index of a git repository, like "git apply":
+ * the index and the work tree together (--index), or the index only
+ * (--cached), which leaves the work tree alone and so works without
+ * the files checked out.
+ *
+ * The paths are relative to the top of the work tree, as in the diffs git makes.
+ */
+use crate::fs::{DiskFileSystem, FileSystem};
+use crate::mbox::Mail;
+use git2::build::CheckoutBuilder;
+use git2::{
+    Commit, DiffFormat, Index, IndexEntry, IndexTime, ObjectType, Oid, Repository, ResetType,
+    Signature, Sort, StatusOptions, Time,
+};
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What is patched along with the index
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GitTarget {
+    /// the work tree too, whose files must match the index
+    Index,
+    /// nothing, the work tree is left as it is
+    Cached,
+}
+
+/// The files in the index of a repository, changed in memory until write_index()
+pub struct GitFileSystem {
+    repo: Repository,
+    index: RefCell<Index>,
+    target: GitTarget,
+}
+
+fn git_err(e: git2::Error) -> io::Error {
+    io::Error::other(e.message().to_string())
+}
+
+fn not_in_index() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "does not exist in index")
+}
+
+fn index_entry(path: &str, id: Oid, mode: u32, size: usize) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size: size as u32,
+        id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    }
+}
+
+impl GitFileSystem {
+    /// The repository the directory is in
+    pub fn discover(dir: &Path, target: GitTarget) -> Result<Self, git2::Error> {
+        let repo = Repository::discover(dir)?;
+        if target == GitTarget::Index && repo.workdir().is_none() {
+            return Err(git2::Error::from_str(
+                "the repository has no work tree, only the index can be patched",
+            ));
+        }
+        let index = RefCell::new(repo.index()?);
+        Ok(GitFileSystem {
+            repo,
+            index,
+            target,
+        })
+    }
+
+    /// Save the changes made to the index
+    pub fn write_index(&self) -> Result<(), git2::Error> {
+        self.index.borrow_mut().write()
+    }
+
+    /// The .git directory
+    pub fn git_dir(&self) -> &Path {
+        self.repo.path()
+    }
+
+    /// The id of the commit checked out, None on an unborn branch
+    pub fn head(&self) -> Option<String> {
+        let head = self.repo.head().ok()?;
+        head.target().map(|id| id.to_string())
+    }
+
+    /// Whether the index or the files tracked in the work tree differ from HEAD
+    pub fn has_changes(&self) -> Result<bool, git2::Error> {
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(false).include_ignored(false);
+        Ok(!self.repo.statuses(Some(&mut opts))?.is_empty())
+    }
+
+    /// Make the index and the work tree as in the commit, and move the branch to it
+    pub fn reset_hard(&self, id: &str) -> Result<(), git2::Error> {
+        let commit = self.repo.find_object(Oid::from_str(id)?, None)?;
+        self.repo.reset(&commit, ResetType::Hard, None)?;
+        self.index.borrow_mut().read(true)
+    }
+
+    /// Check out the branch, keeping the changes in the work tree which do not get in the way
+    pub fn checkout(&self, branch: &str) -> Result<(), git2::Error> {
+        let reference = self.repo.resolve_reference_from_short_name(branch)?;
+        let name = reference
+            .name()
+            .ok_or_else(|| git2::Error::from_str("the branch name is not UTF-8"))?;
+        self.repo.checkout_tree(
+            &reference.peel(ObjectType::Tree)?,
+            Some(CheckoutBuilder::new().safe()),
+        )?;
+        self.repo.set_head(name)?;
+        self.index.borrow_mut().read(true)
+    }
+
+    /*
+     * Commit the index on top of HEAD, with the author and the message given,
+     * and the committer from the git config; returns the id of the new commit.
+     */
+    fn commit_index(&self, author: &Signature, message: &str) -> Result<String, git2::Error> {
+        let mut index = self.index.borrow_mut();
+        index.write()?;
+        let tree = self.repo.find_tree(index.write_tree()?)?;
+        let parent = match self.repo.head() {
+            Ok(head) => Some(head.peel_to_commit()?),
+            Err(_) => None,
+        };
+        if parent
+            .as_ref()
+            .is_some_and(|parent| parent.tree_id() == tree.id())
+        {
+            return Err(git2::Error::from_str("no changes to commit"));
+        }
+        let committer = self.repo.signature()?;
+        let parents: Vec<_> = parent.iter().collect();
+        let id = self
+            .repo
+            .commit(Some("HEAD"), author, &committer, message, &tree, &parents)?;
+        Ok(id.to_string())
+    }
+
+    /// Commit the index with the author and the message of the mail
+    pub fn commit_mail(&self, mail: &Mail) -> Result<String, git2::Error> {
+        let author = match mail.time() {
+            Some((seconds, offset)) => Signature::new(
+                &mail.author_name,
+                &mail.author_email,
+                &Time::new(seconds, offset),
+            )?,
+            None => Signature::now(&mail.author_name, &mail.author_email)?,
+        };
+        self.commit_index(&author, &mail.message())
+    }
+
+    /// Commit the index with the author and the message of the commit
+    pub fn commit_as(&self, id: &str) -> Result<String, git2::Error> {
+        let commit = self.find_commit(id)?;
+        let message = String::from_utf8_lossy(commit.message_bytes()).into_owned();
+        let author = commit.author();
+        self.commit_index(&author, &message)
+    }
+
+    fn find_commit(&self, id: &str) -> Result<Commit<'_>, git2::Error> {
+        self.repo.find_commit(Oid::from_str(id)?)
+    }
+
+    /*
+     * The commits of the range, e.g. "v1.0..main", oldest first, leaving out
+     * the merges; a single revision is that one commit, as for "git cherry-pick".
+     */
+    pub fn commits(&self, range: &str) -> Result<Vec<String>, git2::Error> {
+        if !range.contains("..") {
+            let commit = self.repo.revparse_single(range)?.peel_to_commit()?;
+            if commit.parent_count() > 1 {
+                return Err(git2::Error::from_str(&format!(
+                    "{} is a merge, give the range of the commits to carry over",
+                    range
+                )));
+            }
+            return Ok(vec![commit.id().to_string()]);
+        }
+        let mut walk = self.repo.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        walk.push_range(range)?;
+        let mut commits = vec![];
+        for id in walk {
+            let commit = self.repo.find_commit(id?)?;
+            if commit.parent_count() <= 1 {
+                commits.push(commit.id().to_string());
+            }
+        }
+        Ok(commits)
+    }
+
+    /// The first line of the message of the commit
+    pub fn commit_summary(&self, id: &str) -> Result<String, git2::Error> {
+        let commit = self.find_commit(id)?;
+        Ok(commit.summary().unwrap_or_default().to_string())
+    }
+
+    /// The changes the commit made to its parent, as "git show" has them, renames included
+    pub fn commit_diff(&self, id: &str) -> Result<String, git2::Error> {
+        let commit = self.find_commit(id)?;
+        let parent_tree = match commit.parent_count() {
+            0 => None,
+            _ => Some(commit.parent(0)?.tree()?),
+        };
+        let mut diff =
+            self.repo
+                .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+        diff.find_similar(None)?;
+        let mut text = Vec::new();
+        diff.print(DiffFormat::Patch, |_, _, line| {
+            if let origin @ ('+' | '-' | ' ') = line.origin() {
+                text.push(origin as u8);
+            }
+            text.extend_from_slice(line.content());
+            true
+        })?;
+        Ok(String::from_utf8_lossy(&text).into_owned())
+    }
+
+    fn entry(&self, path: &str) -> io::Result<IndexEntry> {
+        self.index
+            .borrow()
+            .get_path(Path::new(path), 0)
+            .ok_or_else(not_in_index)
+    }
+
+    fn work_path(&self, path: &str) -> String {
+        let workdir = self
+            .repo
+            .workdir()
+            .map_or_else(PathBuf::new, Path::to_path_buf);
+        workdir.join(path).to_string_lossy().into_owned()
+    }
+
+    /// Put the file as it is in the work tree into the index
+    fn add_from_work_tree(&self, path: &str) -> io::Result<()> {
+        self.index
+            .borrow_mut()
+            .add_path(Path::new(path))
+            .map_err(git_err)
+    }
+}
+
+impl FileSystem for GitFileSystem {
+    fn read_to_string(&self, path: &str) -> io::Result<String> {
+        let entry = self.entry(path)?;
+        let blob = self.repo.find_blob(entry.id).map_err(git_err)?;
+        let contents = String::from_utf8(blob.content().to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if self.target == GitTarget::Index
+            && DiskFileSystem.read_to_string(&self.work_path(path))? != contents
+        {
+            return Err(io::Error::other("does not match index"));
+        }
+        Ok(contents)
+    }
+
+    fn write(&self, path: &str, contents: &str) -> io::Result<()> {
+        match self.target {
+            GitTarget::Index => {
+                DiskFileSystem.write(&self.work_path(path), contents)?;
+                self.add_from_work_tree(path)
+            }
+            GitTarget::Cached => {
+                let mode = self.entry(path).map_or(0o100644, |entry| entry.mode);
+                let entry = index_entry(path, Oid::zero(), mode, contents.len());
+                self.index
+                    .borrow_mut()
+                    .add_frombuffer(&entry, contents.as_bytes())
+                    .map_err(git_err)
+            }
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let entry = self.entry(from)?;
+        if self.target == GitTarget::Index {
+            DiskFileSystem.rename(&self.work_path(from), &self.work_path(to))?;
+        }
+        let mut index = self.index.borrow_mut();
+        index.remove_path(Path::new(from)).map_err(git_err)?;
+        index
+            .add(&index_entry(
+                to,
+                entry.id,
+                entry.mode,
+                entry.file_size as usize,
+            ))
+            .map_err(git_err)
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        self.entry(path)?;
+        if self.target == GitTarget::Index {
+            DiskFileSystem.delete(&self.work_path(path))?;
+        }
+        self.index
+            .borrow_mut()
+            .remove_path(Path::new(path))
+            .map_err(git_err)
+    }
+
+    fn set_mode(&self, path: &str, mode: u32) -> io::Result<()> {
+        let entry = self.entry(path)?;
+        if self.target == GitTarget::Index {
+            DiskFileSystem.set_mode(&self.work_path(path), mode)?;
+        }
+        /* git only records whether the file is executable */
+        let mode = if mode & 0o111 != 0 {
+            0o100755
+        } else {
+            0o100644
+        };
+        self.index
+            .borrow_mut()
+            .add(&IndexEntry { mode, ..entry })
+            .map_err(git_err)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.entry(path).is_ok()
+            || (self.target == GitTarget::Index && DiskFileSystem.exists(&self.work_path(path)))
+    }
+}
+
+/// The files of a tree of the repository, read-only
+pub struct TreeFileSystem {
+    repo: Repository,
+    tree: Oid,
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "the git tree is read-only")
+}
+
+impl TreeFileSystem {
+    /// The tree of the revision, e.g. a commit, in the repository the directory is in
+    pub fn discover(dir: &Path, rev: &str) -> Result<Self, git2::Error> {
+        let repo = Repository::discover(dir)?;
+        let tree = repo.revparse_single(rev)?.peel_to_tree()?.id();
+        Ok(TreeFileSystem { repo, tree })
+    }
+
+    fn blob_id(&self, path: &str) -> io::Result<Oid> {
+        let tree = self.repo.find_tree(self.tree).map_err(git_err)?;
+        let entry = tree
+            .get_path(Path::new(path))
+            .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "does not exist in the tree"))?;
+        Ok(entry.id())
+    }
+}
+
+impl FileSystem for TreeFileSystem {
+    fn read_to_string(&self, path: &str) -> io::Result<String> {
+        let blob = self.repo.find_blob(self.blob_id(path)?).map_err(git_err)?;
+        String::from_utf8(blob.content().to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write(&self, _path: &str, _contents: &str) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn delete(&self, _path: &str) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn set_mode(&self, _path: &str, _mode: u32) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.blob_id(path).is_ok()
+    }
+}
+
+/// The blobs of a repository, for the three-way merges
+pub struct BlobStore {
+    repo: Repository,
+}
+
+impl BlobStore {
+    /// The repository the directory is in
+    pub fn discover(dir: &Path) -> Result<Self, git2::Error> {
+        Ok(BlobStore {
+            repo: Repository::discover(dir)?,
+        })
+    }
+
+    /// The text of the blob, by its id as abbreviated in the "index" lines of the diffs
+    pub fn read(&self, id: &str) -> Option<String> {
+        let object = self
+            .repo
+            .find_object_by_prefix(id, Some(ObjectType::Blob))
+            .ok()?;
+        let blob = object.as_blob()?;
+        String::from_utf8(blob.content().to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::{PatchOptions, Patcher};
+    use crate::patchset::PatchSet;
+
+    #[test]
+    fn git_apply_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut index = repo.index().unwrap();
+        let entry = index_entry("f.c", Oid::zero(), 0o100644, 0);
+        index.add_frombuffer(&entry, b"a;\n\tb;\nc;\n").unwrap();
+        index.write().unwrap();
+
+        let fs = GitFileSystem::discover(dir.path(), GitTarget::Cached).unwrap();
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -1,3 +1,4 @@\n a;\n   b;\n+x;\n c;\n";
+        let patch = PatchSet::parse(diff).unwrap();
+        let patcher = Patcher::new(PatchOptions::default());
+        patcher.apply_file_patch(&patch.files()[0], &fs).unwrap();
+        fs.write_index().unwrap();
+
+        let fs = GitFileSystem::discover(dir.path(), GitTarget::Cached).unwrap();
+        assert_eq!(fs.read_to_string("f.c").unwrap(), "a;\n\tb;\nx;\nc;\n");
+        assert!(!dir.path().join("f.c").exists());
+        let fs = GitFileSystem::discover(dir.path(), GitTarget::Index).unwrap();
+        assert!(fs.read_to_string("f.c").is_err());
+    }
+
+    #[test]
+    fn git_commit_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Committer").unwrap();
+        config.set_str("user.email", "c@example.com").unwrap();
+        let fs = GitFileSystem::discover(dir.path(), GitTarget::Cached).unwrap();
+        let author = Signature::now("Author", "a@example.com").unwrap();
+        fs.write("f.c", "a;\nb;\n").unwrap();
+        let base = fs.commit_index(&author, "Base\n").unwrap();
+        fs.write("f.c", "a;\nc;\n").unwrap();
+        let change = fs.commit_index(&author, "Change b\n\nTo c.\n").unwrap();
+        assert_eq!(fs.commits("HEAD").unwrap(), [&change[..]]);
+        assert_eq!(fs.commits(&base).unwrap(), [&base[..]]);
+        assert_eq!(fs.commits("HEAD~1..HEAD").unwrap(), [&change[..]]);
+        assert_eq!(fs.commit_summary(&change).unwrap(), "Change b");
+        let diff = fs.commit_diff(&change).unwrap();
+        assert!(
+            diff.ends_with("@@ -1,2 +1,2 @@\n a;\n-b;\n+c;\n"),
+            "{}",
+            diff
+        );
+
+        fs.reset_hard(&base).unwrap();
+        let patch = PatchSet::parse(&diff).unwrap();
+        let patcher = Patcher::new(PatchOptions::default());
+        patcher.apply_file_patch(&patch.files()[0], &fs).unwrap();
+        let id = fs.commit_as(&change).unwrap();
+        let commit = repo.find_commit(Oid::from_str(&id).unwrap()).unwrap();
+        assert_eq!(commit.author().name(), Some("Author"));
+        assert_eq!(commit.committer().name(), Some("Committer"));
+        assert_eq!(commit.message(), Some("Change b\n\nTo c.\n"));
+        assert!(fs.commit_as(&change).is_err());
+    }
+
+    #[test]
+    fn git_commit_diff_one_line_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Committer").unwrap();
+        config.set_str("user.email", "c@example.com").unwrap();
+        let fs = GitFileSystem::discover(dir.path(), GitTarget::Cached).unwrap();
+        let author = Signature::now("Author", "a@example.com").unwrap();
+        fs.write("edited.c", "a;\n").unwrap();
+        fs.write("gone.c", "g;\n").unwrap();
+        let base = fs.commit_index(&author, "Base\n").unwrap();
+        fs.write("edited.c", "b;\n").unwrap();
+        fs.delete("gone.c").unwrap();
+        fs.write("new.c", "n;\n").unwrap();
+        let change = fs.commit_index(&author, "Change\n").unwrap();
+        let diff = fs.commit_diff(&change).unwrap();
+        /* the way git writes the ranges of one line */
+        assert!(diff.contains("@@ -1 +1 @@\n"), "{}", diff);
+        assert!(diff.contains("@@ -0,0 +1 @@\n"), "{}", diff);
+
+        fs.reset_hard(&base).unwrap();
+        let patch = PatchSet::parse(&diff).unwrap();
+        let patcher = Patcher::new(PatchOptions::default());
+        for file in patch.files() {
+            patcher.apply_file_patch(file, &fs).unwrap();
+        }
+        assert_eq!(fs.read_to_string("edited.c").unwrap(), "b;\n");
+        assert_eq!(fs.read_to_string("new.c").unwrap(), "n;\n");
+        assert!(!fs.exists("gone.c"));
+    }
+}
@@ -0,0 +1,321 @@
+/*
+ * The side-by-side HTML report: each hunk as it is in the patch,
+ * next to the lines of the file it became, with the tokens marked
+ * as the matched context, added by the hunk, or with the whitespace
+ * adapted to the file.
+ */
+use crate::parse::{parse_string_with, ParseStruct, Tokenizer};
+use crate::patch::{
+    find_mismatch, find_needle, line_of_atom, parse_hunk_side, AppliedFile, AppliedHunk,
+    HunkStatus, PatchError,
+};
+use crate::patchset::{FilePatch, Hunk};
+use crate::report::FileReport;
+use std::fmt::{self, Write};
+
+const STYLE: &str = "
+body { font-family: sans-serif; }
+table.hunk { border-collapse: collapse; width: 100%; margin-bottom: 1em; }
+table.hunk td { vertical-align: top; border: 1px solid #ccc; padding: 0.3em; width: 50%; }
+pre { margin: 0; }
+.ln { color: #999; }
+.del { background: #fdd; }
+.add { background: #dfd; }
+.ctx { background: #eef; }
+.ws { background: #fe9; }
+.applied, .merged { color: #080; }
+.fuzzed { color: #a60; }
+.already-applied { color: #06a; }
+.skipped { color: #888; }
+.failed, .rejected, .error { color: #c00; }
+";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The hunk as it is in the patch
+fn patch_side(out: &mut String, hunk: &Hunk) -> fmt::Result {
+    writeln!(
+        out,
+        "@@ -{},{} +{},{} @@ {}",
+        hunk.source_start,
+        hunk.source_length,
+        hunk.target_start,
+        hunk.target_length,
+        escape(&hunk.section_header)
+    )?;
+    for line in hunk.diff_lines() {
+        let text = format!("{}{}", escape(&line.line_type), escape(&line.value));
+        match line.line_type.as_str() {
+            "+" => writeln!(out, "<span class=\"add\">{}</span>", text)?,
+            "-" => writeln!(out, "<span class=\"del\">{}</span>", text)?,
+            _ => writeln!(out, "{}", text)?,
+        }
+    }
+    Ok(())
+}
+
+fn last_line(ws: &str) -> &str {
+    ws.rsplit('\n').next().unwrap_or(ws)
+}
+
+/// The classes of the whitespace and of the token of each atom of the result
+type AtomClasses = Vec<(Option<&'static str>, Option<&'static str>)>;
+
+/// Mark the atoms the applied hunk became, None if they are not in the result
+fn classify(
+    result: &ParseStruct,
+    hunk: &Hunk,
+    applied: &AppliedHunk,
+    tokenizer: Tokenizer,
+) -> Option<(AtomClasses, usize, usize)> {
+    let at_file_start = applied.token_pos == 0;
+    let target_lines = hunk.target_lines();
+    let dst = parse_hunk_side(&target_lines, at_file_start, false, tokenizer).parse;
+    if dst.atoms.is_empty() {
+        return None;
+    }
+    /* the later hunks are usually further down, so look where this one matched first */
+    let from = applied.token_pos.min(result.atoms.len());
+    let pos = find_needle(&dst.atoms, &result.atoms[from..], false)
+        .map(|pos| pos + from)
+        .or_else(|| find_needle(&dst.atoms, &result.atoms, false))?;
+    let mut classes: AtomClasses = vec![(None, None); result.atoms.len()];
+    let mut newlines = 0;
+    for (i, atom) in dst.atoms.iter().enumerate() {
+        newlines += atom.leading_ws.matches('\n').count();
+        /* every line but the very first of the file is preceded by a newline */
+        let line = if at_file_start {
+            newlines
+        } else {
+            newlines.saturating_sub(1)
+        };
+        let added = target_lines.get(line).is_some_and(|l| l.is_added());
+        /* what is before the first line is not the hunk's */
+        let (target_ws, ws) = match i {
+            0 => (
+                last_line(&result.atoms[pos].leading_ws),
+                last_line(&atom.leading_ws),
+            ),
+            _ => (&result.atoms[pos + i].leading_ws[..], &atom.leading_ws[..]),
+        };
+        let ws_class = Some("ws").filter(|_| target_ws != ws);
+        classes[pos + i] = (ws_class, Some(if added { "add" } else { "ctx" }));
+    }
+    let end = pos + dst.atoms.len() - 1;
+    Some((
+        classes,
+        line_of_atom(result, pos),
+        line_of_atom(result, end),
+    ))
+}
+
+/// The lines of the result from first to last, with the marked tokens
+fn result_side(
+    out: &mut String,
+    result: &ParseStruct,
+    classes: &AtomClasses,
+    first: usize,
+    last: usize,
+) -> fmt::Result {
+    let width = last.to_string().len();
+    let shown = first..=last;
+    let push = |out: &mut String, class: Option<&str>, text: &str, line: &mut usize| {
+        let mut parts = text.split('\n').peekable();
+        while let Some(part) = parts.next() {
+            if shown.contains(line) && !part.is_empty() {
+                match class {
+                    Some(class) => {
+                        write!(out, "<span class=\"{}\">{}</span>", class, escape(part))?
+                    }
+                    None => out.push_str(&escape(part)),
+                }
+            }
+            if parts.peek().is_some() {
+                if shown.contains(line) && *line < last {
+                    out.push('\n');
+                }
+                *line += 1;
+                if shown.contains(line) {
+                    write!(out, "<span class=\"ln\">{:>w$}</span> ", line, w = width)?;
+                }
+            }
+        }
+        Ok(())
+    };
+    if first == 1 {
+        write!(out, "<span class=\"ln\">{:>w$}</span> ", 1, w = width)?;
+    }
+    let mut line = 1;
+    for (atom, (ws_class, token_class)) in result.atoms.iter().zip(classes) {
+        push(out, *ws_class, &atom.leading_ws, &mut line)?;
+        push(out, *token_class, &atom.token_value, &mut line)?;
+        if line > last {
+            break;
+        }
+    }
+    writeln!(out)
+}
+
+/// A section of the report for the file
+fn write_file(
+    out: &mut String,
+    file: &FilePatch,
+    result: &Result<AppliedFile, PatchError>,
+    tokenizer: Tokenizer,
+) -> fmt::Result {
+    let report = FileReport::new(file, result);
+    let text = match result {
+        Ok(applied) => Some(&applied.applied.text),
+        Err(PatchError::HunksFailed { applied, .. }) => Some(&applied.text),
+        Err(_) => None,
+    };
+    let applied_hunks = match result {
+        Ok(applied) => Some(&applied.applied.hunks),
+        Err(PatchError::HunksFailed { applied, .. }) => Some(&applied.hunks),
+        Err(_) => None,
+    };
+    let parse = text.map(|text| parse_string_with(text, tokenizer));
+    write!(
+        out,
+        "<h2>{} &rarr; {}",
+        escape(&file.source_file),
+        escape(&file.target_file)
+    )?;
+    if let Some(operation) = report.operation {
+        write!(out, " <small>({})</small>", operation)?;
+    }
+    out.push_str("</h2>\n");
+    if let Some(error) = &report.error {
+        writeln!(out, "<p class=\"error\">{}</p>", escape(error))?;
+    }
+    for (i, (hunk, hunk_report)) in file.hunks().iter().zip(&report.hunks).enumerate() {
+        write!(
+            out,
+            "<h3>Hunk #{} <span class=\"{}\">{}</span>",
+            i + 1,
+            hunk_report.status,
+            hunk_report.status
+        )?;
+        if let Some([start, end]) = hunk_report.lines {
+            write!(
+                out,
+                " at lines {}-{}, offset {}, similarity {:.2}, {} tokens with whitespace adapted",
+                start, end, hunk_report.offset, hunk_report.similarity, hunk_report.ws_adjusted
+            )?;
+        }
+        out.push_str("</h3>\n<table class=\"hunk\"><tr><td><pre>");
+        patch_side(out, hunk)?;
+        out.push_str("</pre></td><td><pre>");
+        let applied = applied_hunks.and_then(|hunks| hunks.get(i));
+        match (applied, &parse) {
+            (Some(applied), Some(parse)) if applied.status.is_applied() => {
+                match classify(parse, hunk, applied, tokenizer) {
+                    Some((classes, first, last)) => result_side(out, parse, &classes, first, last)?,
+                    None => out.push_str("(nothing of the hunk is left in the file)\n"),
+                }
+            }
+            (Some(applied), Some(_)) if applied.status == HunkStatus::Conflict => {
+                writeln!(
+                    out,
+                    "written between conflict markers at lines {}-{}",
+                    applied.line, applied.end_line
+                )?;
+            }
+            (Some(applied), Some(_)) if applied.status == HunkStatus::AlreadyApplied => {
+                writeln!(out, "already in the file at line {}", applied.line)?;
+            }
+            (Some(applied), Some(_)) if applied.status == HunkStatus::Failed => {
+                write!(out, "FAILED at line {}", applied.line)?;
+                if let Some(m) = text.and_then(|text| find_mismatch(text, hunk, tokenizer)) {
+                    write!(
+                        out,
+                        ": {} of {} context tokens match at line {}, then {} is {}",
+                        m.matched,
+                        m.total,
+                        m.line,
+                        escape(&format!("{:?}", m.expected)),
+                        m.found.map_or("past the end of the file".to_string(), |f| {
+                            escape(&format!("{:?}", f))
+                        })
+                    )?;
+                }
+                out.push('\n');
+            }
+            (Some(applied), Some(_)) if applied.status == HunkStatus::Skipped => {
+                out.push_str("skipped when asked\n");
+            }
+            _ => out.push_str("not attempted\n"),
+        }
+        out.push_str("</pre></td></tr></table>\n");
+    }
+    Ok(())
+}
+
+/// The patches applied to the files, each hunk next to what it became
+#[derive(Clone, Debug, Default)]
+pub struct HtmlReport {
+    files: Vec<String>,
+}
+
+impl HtmlReport {
+    /// Add the file, with the tokenizer its hunks were applied with
+    pub fn push(
+        &mut self,
+        file: &FilePatch,
+        result: &Result<AppliedFile, PatchError>,
+        tokenizer: Tokenizer,
+    ) {
+        let mut out = String::new();
+        write_file(&mut out, file, result, tokenizer).unwrap();
+        self.files.push(out);
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>tbpatch report</title>\n<style>{}</style>\n</head>\n<body>\n",
+            STYLE
+        );
+        out.push_str(
+            "<p>Tokens: <span class=\"ctx\">matched context</span> \
+             <span class=\"add\">added</span> <span class=\"ws\">whitespace adapted</span></p>\n",
+        );
+        for file in &self.files {
+            out.push_str(file);
+        }
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::MemoryFileSystem;
+    use crate::patch::{PatchOptions, Patcher};
+    use crate::patchset::PatchSet;
+
+    #[test]
+    fn html_report_marks_tokens() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("f.c", "a;\n\tb;\nc;\n");
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -1,3 +1,4 @@\n a;\n   b;\n+x<y;\n c;\n";
+        let patch = PatchSet::parse(diff).unwrap();
+        let file = &patch.files()[0];
+        let result = Patcher::new(PatchOptions::default()).apply_file_patch(file, &fs);
+        let mut report = HtmlReport::default();
+        report.push(file, &result, Tokenizer::Code);
+        let html = report.to_html();
+        assert!(html.contains("<span class=\"fuzzed\">fuzzed</span>"));
+        assert!(html.contains("<span class=\"add\">+x&lt;y;</span>"));
+        assert!(html.contains(
+            "<span class=\"ln\">2</span> <span class=\"ws\">\t</span><span class=\"ctx\">b</span>"
+        ));
+        assert!(html.contains("<span class=\"add\">x</span><span class=\"add\">&lt;</span>"));
+    }
+}
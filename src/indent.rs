@@ -0,0 +1,523 @@
+/*
+ * Inference of the indentation style of a tokenized text, and the
+ * re-synthesis of the leading whitespace of the atoms inserted by a hunk,
+ * so that the added code follows the style of the file it lands in,
+ * rather than the style of the file the patch was made against.
+ */
+use crate::{ParseStruct, TextAtom};
+use std::collections::HashMap;
+
+const TAB_WIDTH: usize = 8;
+
+/// The formatting properties of a text, None where there was no evidence
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IndentStyle {
+    /// columns per indentation level
+    pub unit: Option<usize>,
+    /// whether each 8 columns of indentation are written as a tab
+    pub use_tabs: Option<bool>,
+    /// whether the opening brace of a block goes on its own line
+    pub brace_own_line: Option<bool>,
+    /// whether such a brace is indented by a level, GNU style
+    pub brace_indented: Option<bool>,
+}
+
+impl IndentStyle {
+    /// Fill in the properties we have no evidence for from the other style
+    pub fn or(&self, other: &IndentStyle) -> IndentStyle {
+        IndentStyle {
+            unit: self.unit.or(other.unit),
+            use_tabs: self.use_tabs.or(other.use_tabs),
+            brace_own_line: self.brace_own_line.or(other.brace_own_line),
+            brace_indented: self.brace_indented.or(other.brace_indented),
+        }
+    }
+
+    fn unit(&self) -> usize {
+        self.unit.unwrap_or(TAB_WIDTH)
+    }
+
+    fn indent_string(&self, col: usize) -> String {
+        if self.use_tabs.unwrap_or(true) {
+            format!(
+                "{}{}",
+                "\t".repeat(col / TAB_WIDTH),
+                " ".repeat(col % TAB_WIDTH)
+            )
+        } else {
+            " ".repeat(col)
+        }
+    }
+
+    pub fn infer(parse: &ParseStruct) -> IndentStyle {
+        let mut deltas: HashMap<usize, usize> = HashMap::new();
+        let mut tab_lines = 0;
+        let mut space_lines = 0;
+        let mut any_tabs = false;
+        let mut braces_own_line = 0;
+        let mut braces_same_line = 0;
+        let mut braces_indented = 0;
+
+        let mut depth = 0;
+        let mut line_col: Option<usize> = None;
+        let mut prev_token = "";
+
+        for atom in &parse.atoms {
+            if atom.token_value.is_empty() {
+                continue;
+            }
+            let indent = line_indent(&atom.leading_ws);
+            if atom.token_value == "{" && depth > 0 && opens_block(prev_token) {
+                match (indent, line_col) {
+                    (Some(indent), Some(prev_col)) => {
+                        braces_own_line += 1;
+                        if indent_columns(indent) > prev_col {
+                            braces_indented += 1;
+                        }
+                    }
+                    (Some(_), None) => braces_own_line += 1,
+                    (None, _) => braces_same_line += 1,
+                }
+            }
+            /* the continuation lines of the comments are aligned, not indented */
+            if let Some(indent) = indent.filter(|_| !atom.token_value.starts_with('*')) {
+                let col = indent_columns(indent);
+                any_tabs = any_tabs || indent.contains('\t');
+                if col >= TAB_WIDTH {
+                    if indent.starts_with('\t') {
+                        tab_lines += 1;
+                    } else {
+                        space_lines += 1;
+                    }
+                }
+                if let Some(prev_col) = line_col {
+                    if col >= prev_col + 2 {
+                        *deltas.entry(col - prev_col).or_insert(0) += 1;
+                    }
+                }
+                line_col = Some(col);
+            }
+            depth = brace_depth_after(depth, &atom.token_value);
+            prev_token = &atom.token_value;
+        }
+
+        let unit = deltas
+            .into_iter()
+            .max_by(|(d1, n1), (d2, n2)| n1.cmp(n2).then(d2.cmp(d1)))
+            .map(|(delta, _)| delta);
+        let use_tabs = if tab_lines + space_lines > 0 {
+            Some(tab_lines > space_lines)
+        } else if any_tabs {
+            Some(true)
+        } else {
+            None
+        };
+        let brace_own_line = if braces_own_line + braces_same_line > 0 {
+            Some(braces_own_line > braces_same_line)
+        } else {
+            None
+        };
+        let brace_indented = if braces_own_line > 0 {
+            Some(braces_indented * 2 > braces_own_line)
+        } else {
+            None
+        };
+        IndentStyle {
+            unit,
+            use_tabs,
+            brace_own_line,
+            brace_indented,
+        }
+    }
+}
+
+/// The indentation of the line that the atom starts, if it does start one
+fn line_indent(ws: &str) -> Option<&str> {
+    ws.rfind('\n').map(|pos| &ws[pos + 1..])
+}
+
+fn indent_columns(indent: &str) -> usize {
+    indent.chars().fold(0, |col, ch| {
+        if ch == '\t' {
+            (col / TAB_WIDTH + 1) * TAB_WIDTH
+        } else {
+            col + 1
+        }
+    })
+}
+
+fn replace_indent(ws: &str, indent: &str) -> String {
+    match ws.rfind('\n') {
+        Some(pos) => format!("{}{}", &ws[..=pos], indent),
+        None => ws.to_string(),
+    }
+}
+
+/// The whitespace with the indentation of the line it starts re-expressed in the other style
+pub fn convert_indent(ws: &str, from: &IndentStyle, to: &IndentStyle) -> String {
+    let (from, to) = (from.or(to), to.or(from));
+    match line_indent(ws) {
+        Some(indent) if from != to => {
+            let col = indent_columns(indent);
+            let col = col / from.unit() * to.unit() + col % from.unit();
+            replace_indent(ws, &to.indent_string(col))
+        }
+        _ => ws.to_string(),
+    }
+}
+
+/// Whether a "{" after this token opens a statement block
+fn opens_block(prev_token: &str) -> bool {
+    prev_token.ends_with(')') || prev_token == "else" || prev_token == "do"
+}
+
+/*
+ * The tokenizer glues the punctuation together, so "){" or "};" are
+ * single tokens. Braces within the character and string literals
+ * are not counted, as far as they are visible from a single token.
+ */
+fn brace_depth_after(depth: usize, token: &str) -> usize {
+    if token.contains('\'') || token.contains('"') {
+        return depth;
+    }
+    token.chars().fold(depth, |depth, ch| match ch {
+        '{' => depth + 1,
+        '}' => depth.saturating_sub(1),
+        _ => depth,
+    })
+}
+
+/// An open brace, with whether it indents the block in patch and in target
+struct OpenBrace {
+    patch_indented: bool,
+    target_indented: bool,
+}
+
+/// Per-line data collected on the first pass over the hunk
+struct LineStart {
+    index: usize,
+    /* indentation levels and the alignment remainder within the patch */
+    levels: isize,
+    remainder: usize,
+    /* the number of open braces which indent the block in the target */
+    target_braces: usize,
+    /* the indentation column in the file, for the atoms matched there */
+    target_col: Option<usize>,
+}
+
+/// Rewrite the leading whitespace of the atoms that came from the patch.
+/// The `region` is the output atoms corresponding one-to-one to the atoms
+/// of `patch`, the target side of the hunk, `origin` is the index in `file`
+/// of those which matched the context, and `file_pos` is where the match
+/// starts. The lines are placed relative to the nearest context line,
+/// so the imprecise inference only affects the relative indentation.
+pub fn reindent_inserted(
+    region: &mut [TextAtom],
+    patch: &ParseStruct,
+    origin: &[Option<usize>],
+    file: &ParseStruct,
+    file_pos: usize,
+    patch_style: &IndentStyle,
+    target_style: &IndentStyle,
+) {
+    let patch_style = patch_style.or(target_style);
+    if patch_style == *target_style {
+        return;
+    }
+    let patch_unit = patch_style.unit() as isize;
+    let target_unit = target_style.unit() as isize;
+
+    let mut depth = file.atoms[..file_pos]
+        .iter()
+        .fold(0, |depth, atom| brace_depth_after(depth, &atom.token_value));
+    let mut braces: Vec<OpenBrace> = vec![];
+    let mut patch_line_col: Option<usize> = None;
+    let mut prev_token = "";
+    let mut line_starts: Vec<LineStart> = vec![];
+    /* inserted braces which move to their own line, with the extra indent */
+    let mut new_line_braces: Vec<(usize, bool)> = vec![];
+
+    for (i, atom) in patch.atoms.iter().enumerate() {
+        let token = atom.token_value.as_str();
+        if token.is_empty() {
+            continue;
+        }
+        let inserted = origin[i].is_none();
+        let patch_indent = line_indent(&atom.leading_ws).map(indent_columns);
+        let file_indent = origin[i]
+            .and_then(|fi| line_indent(&file.atoms[fi].leading_ws))
+            .map(indent_columns);
+
+        let mut starts_line = patch_indent.is_some();
+        if token == "{" && opens_block(prev_token) {
+            let patch_indented = match (patch_indent, patch_line_col) {
+                (Some(col), Some(prev_col)) => col > prev_col,
+                _ => false,
+            };
+            let own_line = if !inserted {
+                file_indent.is_some()
+            } else if depth > 0 {
+                target_style
+                    .brace_own_line
+                    .unwrap_or(patch_indent.is_some())
+            } else {
+                /* leave alone the placement of the braces of the functions */
+                patch_indent.is_some()
+            };
+            let target_indented =
+                own_line && depth > 0 && target_style.brace_indented.unwrap_or(false);
+            if inserted && own_line != patch_indent.is_some() {
+                if own_line {
+                    new_line_braces.push((i, target_indented));
+                } else {
+                    region[i].leading_ws = " ".to_string();
+                }
+                starts_line = false;
+            }
+            braces.push(OpenBrace {
+                patch_indented,
+                target_indented,
+            });
+        }
+
+        if let (true, Some(col)) = (starts_line, patch_indent) {
+            let patch_braces = braces.iter().filter(|b| b.patch_indented).count();
+            let target_braces = braces.iter().filter(|b| b.target_indented).count();
+            let col = col as isize - patch_braces as isize * patch_unit;
+            line_starts.push(LineStart {
+                index: i,
+                levels: col.div_euclid(patch_unit),
+                remainder: col.rem_euclid(patch_unit) as usize,
+                target_braces,
+                target_col: file_indent.filter(|_| !inserted),
+            });
+        }
+        if patch_indent.is_some() {
+            patch_line_col = patch_indent;
+        }
+
+        /* the closing brace lines are indented as the block they close */
+        let new_depth = brace_depth_after(depth, token);
+        for _ in new_depth..depth {
+            braces.pop();
+        }
+        if token != "{" {
+            for _ in depth..new_depth {
+                braces.push(OpenBrace {
+                    patch_indented: false,
+                    target_indented: false,
+                });
+            }
+        }
+        depth = new_depth;
+        prev_token = token;
+    }
+
+    for n in 0..line_starts.len() {
+        let ls = &line_starts[n];
+        if origin[ls.index].is_some() {
+            continue;
+        }
+        /* the anchor is the nearest context line, preferably a preceding one */
+        let anchor = line_starts[..n]
+            .iter()
+            .rev()
+            .find(|a| origin[a.index].is_some() && a.target_col.is_some())
+            .or_else(|| {
+                line_starts[n + 1..]
+                    .iter()
+                    .find(|a| origin[a.index].is_some() && a.target_col.is_some())
+            });
+        let (anchor_col, anchor_levels, anchor_braces) = match anchor {
+            Some(a) => (a.target_col.unwrap() as isize, a.levels, a.target_braces),
+            None => (0, 0, 0),
+        };
+        let levels = ls.levels - anchor_levels + ls.target_braces as isize - anchor_braces as isize;
+        let col = (anchor_col + levels * target_unit + ls.remainder as isize).max(0) as usize;
+        region[ls.index].leading_ws = replace_indent(
+            &region[ls.index].leading_ws,
+            &target_style.indent_string(col),
+        );
+        line_starts[n].target_col = Some(col);
+    }
+
+    /* the braces which now start a line follow the line they were on */
+    for (i, indented) in new_line_braces {
+        let line_col = line_starts
+            .iter()
+            .rev()
+            .find(|ls| ls.index < i)
+            .and_then(|ls| ls.target_col)
+            .unwrap_or(0);
+        let col = line_col + if indented { target_style.unit() } else { 0 };
+        region[i].leading_ws = format!("\n{}", target_style.indent_string(col));
+    }
+}
+
+/// The whitespace split into the trailing part of the previous line,
+/// the newlines with the blank lines between them, and the indentation
+fn split_ws(ws: &str) -> (&str, &str, Option<&str>) {
+    match (ws.find('\n'), ws.rfind('\n')) {
+        (Some(first), Some(last)) => (&ws[..first], &ws[first..=last], Some(&ws[last + 1..])),
+        _ => (ws, "", None),
+    }
+}
+
+/// Carry over the change the hunk makes to the whitespace, from `src`
+/// to `dst`, onto the whitespace the atom has in the target file, which
+/// may differ from `src` if the file is formatted differently. The changes
+/// to the indentation are scaled from the units of the patch to those
+/// of the target file.
+pub fn apply_ws_delta(
+    src: &str,
+    dst: &str,
+    target: &str,
+    patch_style: &IndentStyle,
+    target_style: &IndentStyle,
+) -> String {
+    if src == dst {
+        return target.to_string();
+    }
+    if target == src {
+        return dst.to_string();
+    }
+    let (src_pre, src_newlines, src_indent) = split_ws(src);
+    let (dst_pre, dst_newlines, dst_indent) = split_ws(dst);
+    let (target_pre, target_newlines, target_indent) = split_ws(target);
+    let (src_indent, dst_indent, target_indent) = match (src_indent, dst_indent, target_indent) {
+        (Some(s), Some(d), Some(t)) => (s, d, t),
+        /* the lines are joined, or split or respaced where the file has them the same */
+        (Some(_), None, _) | (None, _, None) => return dst.to_string(),
+        /* the file already has these atoms on separate lines, or on the same one */
+        (None, _, Some(_)) | (Some(_), Some(_), None) => return target.to_string(),
+    };
+
+    let pre = if src_pre != dst_pre {
+        dst_pre
+    } else {
+        target_pre
+    };
+    let newline_count = target_newlines.matches('\n').count() as isize
+        + dst_newlines.matches('\n').count() as isize
+        - src_newlines.matches('\n').count() as isize;
+    let newlines = if src_newlines == dst_newlines {
+        target_newlines.to_string()
+    } else {
+        "\n".repeat(newline_count.max(1) as usize)
+    };
+    let indent = if src_indent == dst_indent {
+        target_indent.to_string()
+    } else {
+        let patch_unit = patch_style.or(target_style).unit() as isize;
+        let target_unit = target_style.unit() as isize;
+        let delta = indent_columns(dst_indent) as isize - indent_columns(src_indent) as isize;
+        let col = indent_columns(target_indent) as isize
+            + delta / patch_unit * target_unit
+            + delta % patch_unit;
+        /* the patch may as well be converting between tabs and spaces */
+        let use_tabs = if dst_indent.contains('\t') {
+            true
+        } else if indent_columns(dst_indent) >= TAB_WIDTH {
+            false
+        } else {
+            target_indent.contains('\t')
+        };
+        let style = IndentStyle {
+            use_tabs: Some(use_tabs),
+            ..*target_style
+        };
+        style.indent_string(col.max(0) as usize)
+    };
+    format!("{}{}{}", pre, newlines, indent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_string;
+    use crate::patch::Patcher;
+    use crate::patchset::PatchSet;
+
+    const LINUX: IndentStyle = IndentStyle {
+        unit: Some(8),
+        use_tabs: Some(true),
+        brace_own_line: Some(false),
+        brace_indented: None,
+    };
+    const GNU: IndentStyle = IndentStyle {
+        unit: Some(2),
+        use_tabs: Some(false),
+        brace_own_line: Some(true),
+        brace_indented: Some(true),
+    };
+
+    fn infer(text: &str) -> IndentStyle {
+        IndentStyle::infer(&parse_string(text))
+    }
+
+    #[test]
+    fn indent_infer_tabs_and_spaces() {
+        assert_eq!(infer("int f()\n{\n\tif (a) {\n\t\tb();\n\t}\n}\n"), LINUX);
+        let spaces = infer("int f()\n{\n    if (a) {\n        b();\n    }\n}\n");
+        assert_eq!((spaces.unit, spaces.use_tabs), (Some(4), Some(false)));
+    }
+
+    #[test]
+    fn indent_infer_gnu() {
+        let gnu = infer("int f()\n{\n  if (a)\n    {\n      b();\n    }\n}\n");
+        /* no line is indented enough to tell tabs from spaces */
+        assert_eq!(
+            gnu,
+            IndentStyle {
+                use_tabs: None,
+                ..GNU
+            }
+        );
+    }
+
+    #[test]
+    fn reindent_inserted_lines() {
+        let patch = parse_string("\nif (a) {\n    b();\n}");
+        let file = parse_string("\tx;\n");
+        let mut region = patch.atoms.clone();
+        let origin = vec![None; region.len()];
+        let spaces = infer("f()\n{\n    g();\n}\n");
+        reindent_inserted(&mut region, &patch, &origin, &file, 0, &spaces, &LINUX);
+        let region = ParseStruct { atoms: region };
+        assert_eq!(
+            crate::parse::parse_struct2str(&region),
+            "\nif (a) {\n\tb();\n}"
+        );
+    }
+
+    #[test]
+    fn reindent_gnu_hunk_into_linux_file() {
+        let linux = "int f(void)\n{\n\tif (a) {\n\t\tb();\n\t}\n\treturn 0;\n}\n";
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -1,8 +1,12 @@\n int f(void)\n {\n   if (a)\n     {\n\
+                    \x20      b();\n+      if (c)\n+        {\n+          d();\n+        }\n     }\n   return 0;\n }\n";
+        let patch = PatchSet::parse(diff).unwrap();
+        let hunk = &patch.files()[0].hunks()[0];
+        let applied = Patcher::default().apply_to_str(linux, hunk).unwrap();
+        assert_eq!(
+            applied.text,
+            "int f(void)\n{\n\tif (a) {\n\t\tb();\n\t\tif (c) {\n\t\t\td();\n\t\t}\n\t}\n\treturn 0;\n}\n"
+        );
+    }
+
+    #[test]
+    fn ws_delta_scaled() {
+        /* a level deeper in the patch is a level deeper in the file */
+        assert_eq!(
+            apply_ws_delta("\n  ", "\n    ", "\n\t", &GNU, &LINUX),
+            "\n\t\t"
+        );
+        assert_eq!(
+            apply_ws_delta("\n    ", "\n  ", "\n\t\t", &GNU, &LINUX),
+            "\n\t"
+        );
+        /* unchanged by the hunk, the file keeps its own */
+        assert_eq!(apply_ws_delta("\n  ", "\n  ", "\n\t", &GNU, &LINUX), "\n\t");
+        /* a line joined by the hunk */
+        assert_eq!(apply_ws_delta("\n  ", " ", "\n\t", &GNU, &LINUX), " ");
+    }
+}
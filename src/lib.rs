@@ -0,0 +1,50 @@
+//! Token-based patching: both the text being patched and the two sides
+//! of each hunk are split into (leading whitespace, token) atoms, and the
+//! hunks are matched and applied ignoring the whitespace, so a patch made
+//! against one formatting of the code applies to another one.
+//!
+//! ```no_run
+//! use tbpatch::{DiskFileSystem, PatchOptions, PatchSet, Patcher};
+//!
+//! let patchset = PatchSet::parse(&std::fs::read_to_string("fix.diff").unwrap()).unwrap();
+//! let patcher = Patcher::new(PatchOptions::default());
+//! patcher.apply_patchset(&patchset, &DiskFileSystem).unwrap();
+//! ```
+
+mod config;
+mod fs;
+mod git;
+mod html;
+mod indent;
+mod mbox;
+mod merge;
+mod parse;
+mod patch;
+mod patchset;
+mod print;
+mod rebase;
+mod report;
+mod unified;
+mod worddiff;
+
+pub use crate::config::{Config, ConfigError, CONFIG_FILE_NAME};
+pub use crate::fs::{
+    DirFileSystem, DiskFileSystem, FileSystem, MemoryFile, MemoryFileSystem, OverlayFileSystem,
+};
+pub use crate::git::{BlobStore, GitFileSystem, GitTarget, TreeFileSystem};
+pub use crate::html::HtmlReport;
+pub use crate::mbox::{split_mbox, Mail};
+pub use crate::merge::{merge3, MergeOptions, Merged};
+pub use crate::parse::{
+    atom2str, parse_string, parse_string_with, parse_struct2str, ParseStruct, TextAtom, Tokenizer,
+};
+pub use crate::patch::{
+    find_mismatch, find_needle, Applied, AppliedFile, AppliedHunk, FileOperation, HunkCandidate,
+    HunkChoice, HunkStatus, MergeStyle, Mismatch, PatchError, PatchOptions, Patcher, WsPolicy,
+};
+pub use crate::patchset::{get_truncated_file_name, FilePatch, Hunk, PatchSet, DEV_NULL};
+pub use crate::print::{format_diff, format_diff_c, print_diff, print_diff_c};
+pub use crate::rebase::{rebase_file_patch, Rebased};
+pub use crate::report::{CommitReport, FileReport, HunkReport, LineSpan, Report};
+pub use crate::unified::{refreshed_file_patch, unified_diff};
+pub use crate::worddiff::{word_diff, WordDiffMode, WordDiffOptions, WordDiffStats};
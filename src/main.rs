@@ -1,597 +1,1510 @@
 use ansi_term::Colour;
-use clap::Clap;
-use diffus::edit::{self, collection};
-use diffus::{Diffable, Same};
-use diffus_derive::Diffus;
+use clap::{AppSettings, Clap};
+use log::{debug, error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use std::collections::BTreeSet;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tbpatch::{
+    find_mismatch, merge3, parse_string_with, rebase_file_patch, refreshed_file_patch, split_mbox,
+    unified_diff, word_diff, BlobStore, CommitReport, Config, DirFileSystem, DiskFileSystem,
+    FileSystem, GitFileSystem, GitTarget, HtmlReport, Hunk, HunkCandidate, HunkChoice, HunkStatus,
+    Mail, MergeOptions, MergeStyle, OverlayFileSystem, ParseStruct, PatchError, PatchOptions,
+    PatchSet, Patcher, Report, Tokenizer, TreeFileSystem, WordDiffMode, WordDiffOptions,
+    WordDiffStats, WsPolicy, DEV_NULL,
+};
 
 /// This doc string acts as a help message when the user runs '--help'
 /// as do all doc strings on fields
 #[derive(Clap, Clone, Debug)]
 #[clap(version = "0.1", author = "Andrew Yourtchenko <ayourtch@gmail.com>")]
+/*
+ * The subcommands can be abbreviated to any unambiguous prefix, "ch" for "check".
+ * Clap then also stops taking a diff named like "apply.diff" for a mistyped
+ * subcommand, but a diff named like a prefix, "ch", must come after "--" or "apply".
+ */
+#[clap(setting = AppSettings::InferSubcommands)]
 struct Opts {
-    /// Sets a custom config file. Could have been an Option<T> with no default too
-    #[clap(short, long, default_value = "default.conf")]
-    config: String,
-    /// Strip the smallest prefix containing this many leading slashes: FIXME
-    #[clap(short = 'p', long, default_value = "0")]
-    strip: usize,
+    /// Read the settings from this file instead of the .tbpatch.toml
+    /// in the current directory or the closest one above it
+    #[clap(short, long, global = true)]
+    config: Option<String>,
+    /// Strip the smallest prefix containing this many leading slashes [default: 1]
+    #[clap(short = 'p', long, global = true)]
+    strip: Option<usize>,
     /*
     #[clap(index = 1)]
     /// file name to patch
     target_fname: Option<String>,
     #[clap(index = 2)]
     */
-    /// file name with a diff to apply
+    /// file name with a diff to apply, same as "apply"
     diff_fname: Option<String>,
 
     /// A level of verbosity, and can be used multiple times
-    #[clap(short, long, parse(from_occurrences))]
+    #[clap(short, long, parse(from_occurrences), global = true)]
     verbose: i32,
-    //#[clap(subcommand)]
-    // subcmd: SubCommand,
-}
+    /// Only report the errors
+    #[clap(short, long, global = true)]
+    quiet: bool,
 
-#[derive(Clap, Clone, Debug)]
-enum SubCommand {
-    #[clap(version = "1.3", author = "Someone E. <someone_else@other.com>")]
-    Test(Test),
+    /// Whose whitespace wins: keep-target, take-patch or infer [default: infer]
+    #[clap(long, global = true)]
+    ws: Option<WsPolicy>,
+    /// Apply the whitespace changes of the hunks which only change the whitespace
+    #[clap(long, global = true, overrides_with = "no-ws-only-edits")]
+    ws_only_edits: bool,
+    /// Leave the hunks which only change the whitespace out, even if the config file has them applied
+    #[clap(long, global = true, overrides_with = "ws-only-edits")]
+    no_ws_only_edits: bool,
+    /// How to split the files into tokens: code or words [default: code]
+    #[clap(long, global = true)]
+    tokenizer: Option<Tokenizer>,
+    /// Fail the hunks with less of the whitespace of their context matching, 0 to 1 [default: 0]
+    #[clap(long, global = true)]
+    min_similarity: Option<f64>,
+    /// Keep the original of each file changed, with ".orig" appended to its name
+    #[clap(short, long, global = true, overrides_with = "no-backup")]
+    backup: bool,
+    /// Do not keep the originals, even if the config file has them kept
+    #[clap(long, global = true, overrides_with = "backup")]
+    no_backup: bool,
+    /// Patch the files in the index of the git repository and in the work tree, like "git apply --index"
+    #[clap(long, global = true, conflicts_with = "cached")]
+    index: bool,
+    /// Patch the files in the index of the git repository only, like "git apply --cached"
+    #[clap(long, global = true)]
+    cached: bool,
+    /// When the hunks do not apply, apply them to the original the git diff was made against,
+    /// found in the repository by its blob id, and merge that into the file
+    #[clap(short = '3', long = "3way", global = true)]
+    three_way: bool,
+    /// Ask what to do with each hunk, showing what it would change at each place its context is,
+    /// like "git add -p"; the answers are read from stdin
+    #[clap(long, global = true, conflicts_with = "three-way")]
+    interactive: bool,
+    /// Print a report of what became of each file and hunk to stdout: json
+    #[clap(long, global = true)]
+    report: Option<ReportFormat>,
+    /// Write a page with each hunk next to what it became in the file, for reviewing
+    #[clap(long, global = true)]
+    html_report: Option<String>,
+    /// Write a diff of the changes made, as they are in the files, to apply with "patch" or "git apply"
+    #[clap(long, global = true)]
+    emit_refreshed_patch: Option<String>,
+    /// Write the hunks which do not apply between conflict markers: merge or diff3 [default: merge];
+    /// none to fail them, even if the config file has them merged
+    #[clap(long, global = true, min_values = 0, require_equals = true)]
+    merge: Option<Option<MergeArg>>,
+    /// Show the changes token by token: plain, color or porcelain [default: plain];
+    /// for apply and check, the changes made to each file
+    #[clap(long, global = true, min_values = 0, require_equals = true)]
+    word_diff: Option<Option<WordDiffMode>>,
+    /// Show the tokens only respaced as unchanged in the word diff
+    #[clap(short = 'w', long, global = true)]
+    ignore_ws_changes: bool,
+    /// Put the old and the new line numbers in front of the lines of the word diff,
+    /// as a "#OLD NEW" line in porcelain
+    #[clap(short = 'n', long, global = true)]
+    line_numbers: bool,
+    /// When to use colors: auto, always or never [default: auto, on a terminal]
+    #[clap(long, global = true)]
+    color: Option<ColorWhen>,
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
 }
 
-/// A subcommand for controlling testing
-#[derive(Clap, Clone, Debug)]
-struct Test {
-    /// Print debug info
-    #[clap(short)]
-    debug: bool,
-}
-
-#[derive(Diffus, Debug, Clone)]
-struct TextAtom {
-    token_value: String,
-    token_uuid: String,
-    leading_ws: String,
-}
-
-impl Same for TextAtom {
-    fn same(&self, other: &Self) -> bool {
-        if self.token_uuid == "" {
-            if other.token_uuid == "" {
-                /* we assume that with empty IDs the tokens can match */
-                self.token_value == other.token_value
-            } else {
-                false // one tagged, the other not = no match
-            }
+impl Opts {
+    fn git_target(&self) -> Option<GitTarget> {
+        if self.index {
+            Some(GitTarget::Index)
+        } else if self.cached {
+            Some(GitTarget::Cached)
         } else {
-            if other.token_uuid == "" {
-                false // one tagged, the other not = no match
-            } else {
-                self.token_uuid == other.token_uuid && self.token_value == other.token_value
+            None
+        }
+    }
+}
+
+/// The options from the command line on top of the ones from the config file
+struct Settings {
+    options: PatchOptions,
+    config: Config,
+    /// from the command line, overriding the config file
+    tokenizer: Option<Tokenizer>,
+    /// the tokenizer and the context are set per use
+    word_diff: WordDiffOptions,
+    /// whether the word diffs are asked for
+    show_word_diff: bool,
+}
+
+impl Settings {
+    fn new(opts: &Opts) -> Self {
+        let config_path = match &opts.config {
+            Some(path) => Some(PathBuf::from(path)),
+            None => std::env::current_dir()
+                .ok()
+                .and_then(|dir| Config::discover(&dir)),
+        };
+        let config = match config_path {
+            Some(path) => {
+                debug!("config: {}", path.display());
+                Config::load(&path).unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(2);
+                })
             }
+            None => Config::default(),
+        };
+        let defaults = config.options();
+        let options = PatchOptions {
+            strip: opts.strip.unwrap_or(defaults.strip),
+            ws: opts.ws.unwrap_or(defaults.ws),
+            ws_only_edits: (opts.ws_only_edits || defaults.ws_only_edits) && !opts.no_ws_only_edits,
+            min_similarity: opts.min_similarity.unwrap_or(defaults.min_similarity),
+            /* the index keeps the originals */
+            backup: (opts.backup || defaults.backup)
+                && !opts.no_backup
+                && opts.git_target().is_none(),
+            merge: match opts.merge {
+                Some(Some(MergeArg(style))) => style,
+                Some(None) => Some(MergeStyle::Merge),
+                None => defaults.merge,
+            },
+            ..defaults
+        };
+        /* git runs the external diffs with the output going to its pager */
+        let to_terminal =
+            std::io::stdout().is_terminal() || std::env::var_os("GIT_PAGER_IN_USE").is_some();
+        let word_diff = WordDiffOptions {
+            mode: opts.word_diff.flatten().unwrap_or(WordDiffMode::Plain),
+            color: opts.color.unwrap_or_default().enabled(to_terminal),
+            line_numbers: opts.line_numbers,
+            ignore_ws: opts.ignore_ws_changes,
+            ..Default::default()
+        };
+        Settings {
+            options,
+            config,
+            tokenizer: opts.tokenizer,
+            word_diff,
+            show_word_diff: opts.word_diff.is_some(),
         }
     }
+
+    /// The word diff between the two versions of the file
+    fn render_word_diff(&self, path: &str, old: &str, new: &str, context: usize) -> String {
+        let opts = WordDiffOptions {
+            tokenizer: self.patcher_for(path).options().tokenizer,
+            context,
+            ..self.word_diff
+        };
+        word_diff(old, new, &opts)
+    }
+
+    /// The word diff between the two versions of the file, if asked for
+    fn word_diff(&self, path: &str, old: &str, new: &str, context: usize) -> Option<String> {
+        Some(self.render_word_diff(path, old, new, context)).filter(|_| self.show_word_diff)
+    }
+
+    fn patcher_for(&self, path: &str) -> Patcher {
+        let tokenizer = self
+            .tokenizer
+            .or_else(|| self.config.tokenizer_for(path))
+            .unwrap_or(self.options.tokenizer);
+        Patcher::new(PatchOptions {
+            tokenizer,
+            ..self.options
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReportFormat {
+    Json,
 }
 
-impl PartialEq for TextAtom {
-    fn eq(&self, other: &Self) -> bool {
-        self.token_value == other.token_value
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(format!("unknown report format '{}', expected json", s)),
+        }
     }
 }
 
-#[derive(Diffus, Debug, Clone)]
-struct ParseStruct {
-    atoms: Vec<TextAtom>,
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum ColorWhen {
+    #[default]
+    Auto,
+    Always,
+    Never,
 }
 
-enum ParseTokenState {
-    LeadingWhiteSpace,
-    TokenValue,
+impl ColorWhen {
+    fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            ColorWhen::Auto => is_terminal && std::env::var_os("NO_COLOR").is_none(),
+            ColorWhen::Always => true,
+            ColorWhen::Never => false,
+        }
+    }
 }
 
-fn is_ident(c: char) -> bool {
-    return c.is_ascii_alphanumeric() || c == '_';
+impl FromStr for ColorWhen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorWhen::Auto),
+            "always" => Ok(ColorWhen::Always),
+            "never" => Ok(ColorWhen::Never),
+            _ => Err(format!(
+                "unknown color setting '{}', expected auto, always or never",
+                s
+            )),
+        }
+    }
 }
 
-fn parse_token(input: &str, i: usize) -> (Option<TextAtom>, usize) {
-    let mut atom = TextAtom {
-        token_value: format!(""),
-        token_uuid: format!(""),
-        leading_ws: format!(""),
-    };
+/// The --merge style, None for "none"
+#[derive(Clone, Copy, Debug)]
+struct MergeArg(Option<MergeStyle>);
 
-    if i >= input.len() {
-        return (None, 0);
-    }
-
-    let mut state = ParseTokenState::LeadingWhiteSpace;
-    let mut is_id = false;
-
-    let char_indices = input[i..].char_indices();
-    for (ci, ch) in char_indices {
-        match state {
-            ParseTokenState::LeadingWhiteSpace => {
-                if ch.is_whitespace() || (ch == '\\') {
-                    /*
-                     * testing only for "\" in this case is a gross simplification,
-                     * since we really should test for "\" followed by "\n", however
-                     * if we are in the "leading whitespace" state, there should be
-                     * no other valid scenario where "\" may appear, so this
-                     * shortcut should work.
-                     */
-                    atom.leading_ws.push(ch)
-                } else {
-                    atom.token_value.push(ch);
-                    is_id = is_ident(ch);
-                    state = ParseTokenState::TokenValue;
-                }
-            }
-            ParseTokenState::TokenValue => {
-                if ch.is_whitespace() || is_id != is_ident(ch) {
-                    return (Some(atom), ci);
-                } else {
-                    atom.token_value.push(ch);
-                }
-            }
+impl FromStr for MergeArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(MergeArg(None)),
+            s => s
+                .parse()
+                .map(|style| MergeArg(Some(style)))
+                .map_err(|_| format!("unknown merge style '{}', expected merge, diff3 or none", s)),
         }
     }
+}
+
+#[derive(Clap, Clone, Debug)]
+enum SubCommand {
+    /// Apply a diff to the files
+    Apply(DiffFile),
+    /// Check whether a diff applies, without changing the files
+    Check(DiffFile),
+    /// Write a unified diff between two files, found comparing them token by token
+    Diff(DiffArgs),
+    /// Show how a file is split into tokens
+    Tokens(OneFile),
+    /// Show why the hunks of a diff do not apply
+    Explain(DiffFile),
+    /// Merge the changes made to a file on two sides, token by token, to stdout
+    Merge(MergeArgs),
+    /// Merge as the driver of git: "tbpatch merge-driver %O %A %B %L %P" in merge.<name>.driver
+    MergeDriver(MergeDriverArgs),
+    /// Carry a diff made against one tree over to another one which only has it reformatted,
+    /// writing the diff against that to stdout
+    RebasePatch(RebasePatchArgs),
+    /// Apply the patches mailed in an mbox, committing each one, like "git am"
+    Am(AmArgs),
+    /// Carry the commits of a range over to a branch which has the code reformatted,
+    /// committing each one which applies, like "git cherry-pick"
+    Backport(BackportArgs),
+    /// Show the token-level changes to git: GIT_EXTERNAL_DIFF="tbpatch external-diff"
+    ExternalDiff(ExternalDiffArgs),
+    /// Show the token-level changes for "git difftool": tbpatch difftool "$LOCAL" "$REMOTE"
+    Difftool(DifftoolArgs),
+}
+
+#[derive(Clap, Clone, Debug)]
+struct DiffFile {
+    /// file name with a diff, stdin if not given
+    diff_fname: Option<String>,
+}
+
+#[derive(Clap, Clone, Debug)]
+struct DiffArgs {
+    old_fname: String,
+    new_fname: String,
+    /// How many lines of context to show around the changes
+    #[clap(short = 'U', long, default_value = "3")]
+    unified: usize,
+    /// Compare the files in the two directories, and the directories in them
+    #[clap(short, long)]
+    recursive: bool,
+}
+
+#[derive(Clap, Clone, Debug)]
+struct MergeArgs {
+    base_fname: String,
+    ours_fname: String,
+    theirs_fname: String,
+    /// Show the base in the conflicts too
+    #[clap(long)]
+    diff3: bool,
+}
+
+#[derive(Clap, Clone, Debug)]
+struct MergeDriverArgs {
+    /// the base version (%O)
+    base_fname: String,
+    /// our version (%A), which gets the result
+    ours_fname: String,
+    /// their version (%B)
+    theirs_fname: String,
+    /// the length of the conflict markers (%L)
+    marker_size: Option<usize>,
+    /// the path of the file being merged (%P), for the config and the labels
+    path: Option<String>,
+    /// Show the base in the conflicts too
+    #[clap(long)]
+    diff3: bool,
+}
+
+#[derive(Clap, Clone, Debug)]
+struct RebasePatchArgs {
+    /// the tree the diff was made against: a directory, or a revision of the git repository
+    #[clap(long)]
+    old_tree: String,
+    /// the tree to make the diff against, with the same code formatted differently
+    #[clap(long)]
+    new_tree: String,
+    /// file name with a diff, stdin if not given
+    diff_fname: Option<String>,
+}
+
+#[derive(Clap, Clone, Debug)]
+struct AmArgs {
+    /// mbox files with the patches, stdin if none are given
+    mbox_fnames: Vec<String>,
+    /// Commit the patch which failed, once its changes have been made and added to the index,
+    /// and go on with the rest
+    #[clap(long = "continue", conflicts_with_all = &["skip", "abort"])]
+    resume: bool,
+    /// Drop the patch which failed, and go on with the rest
+    #[clap(long, conflicts_with = "abort")]
+    skip: bool,
+    /// Go back to the commit before the first patch
+    #[clap(long)]
+    abort: bool,
+}
+
+#[derive(Clap, Clone, Debug)]
+struct BackportArgs {
+    /// the commits to carry over: "A..B", or just "B" for that one commit
+    range: String,
+    /// the branch to check out and commit to, the current one if not given
+    #[clap(long)]
+    onto: Option<String>,
+}
+
+#[derive(Clap, Clone, Debug)]
+struct ExternalDiffArgs {
+    /// path old-file old-hex old-mode new-file new-hex new-mode [new-path rename-info],
+    /// or just the path for the unmerged files
+    #[clap(min_values = 1, max_values = 9)]
+    args: Vec<String>,
+}
+
+#[derive(Clap, Clone, Debug)]
+struct DifftoolArgs {
+    local_fname: String,
+    remote_fname: String,
+    /// the path shown, $MERGED if not given
+    path: Option<String>,
+}
 
-    (Some(atom), input[i..].len())
+#[derive(Clap, Clone, Debug)]
+struct OneFile {
+    fname: String,
 }
 
-fn parse_string(input: &str) -> ParseStruct {
-    let mut atoms: Vec<TextAtom> = vec![];
-    let mut i = 0;
+/// The diagnostics go to stderr, keeping stdout for the output proper
+struct StderrLogger;
+
+/// Whether the diagnostics are colored
+static STDERR_COLOR: AtomicBool = AtomicBool::new(false);
 
-    while let (Some(token), delta_i) = parse_token(input, i) {
-        // println!("Token: {:#?}, delta_i: {}", &token, delta_i);
-        atoms.push(token);
-        i = i + delta_i;
+fn paint(colour: Colour, text: &str) -> String {
+    if STDERR_COLOR.load(Ordering::Relaxed) {
+        colour.paint(text).to_string()
+    } else {
+        text.to_string()
     }
-    ParseStruct { atoms }
 }
 
-fn parse_file(fname: &str) -> ParseStruct {
-    let fdata = std::fs::read_to_string(fname);
-    if fdata.is_err() {
-        eprintln!("Error: {:?} opening file {}", &fdata, fname);
-        if fname == "dev/null" {
-            return parse_string("");
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
         }
-    } else {
-        eprintln!("opened file {}", fname);
-    }
-    let fdata = fdata.unwrap();
-    parse_string(&fdata)
-}
-
-fn read_nth_arg(i: usize) -> String {
-    let fname = std::env::args().nth(i).unwrap();
-    let fdata = std::fs::read_to_string(fname).unwrap();
-    fdata
-}
-
-fn parse_nth_arg(i: usize) -> ParseStruct {
-    let fdata = read_nth_arg(i);
-    parse_string(&fdata)
-}
-
-fn parse_struct2str(p: &ParseStruct) -> String {
-    let mut out_acc = String::new();
-    for atom in &p.atoms {
-        out_acc.push_str(&atom2str(&atom));
-    }
-    out_acc
-}
-
-fn print_diff<'a>(diff: diffus::edit::Edit<'a, ParseStruct>) {
-    match diff {
-        edit::Edit::Copy(x) => {
-            println!("Identical parses: {:#?}", &x);
-        }
-        edit::Edit::Change(EditedParseStruct { atoms }) => {
-            let diff = atoms;
-            match diff {
-                edit::Edit::Copy(x) => println!("no difference: {:?}", &x),
-                edit::Edit::Change(diff) => {
-                    diff.into_iter()
-                        .map(|edit| {
-                            match edit {
-                                collection::Edit::Copy(elem) => println!("copy: {:?}", elem),
-                                collection::Edit::Insert(elem) => println!("insert: {:?}", elem),
-                                collection::Edit::Remove(elem) => println!("remove: {:?}", elem),
-                                collection::Edit::Change(EditedTextAtom {
-                                    token_value,
-                                    token_uuid,
-                                    leading_ws,
-                                }) => {
-                                    println!("changed:");
-                                    match token_value {
-                                        edit::Edit::Copy(x) => println!("    copy: id {:?}", &x),
-                                        x => {
-                                            println!("    changed: id {:?}", &x);
-                                        } /*
-                                          edit::Edit::Change((left_id, right_id)) => {
-                                              println!("    token_value: {} => {}", left_id, right_id)
-                                          }
-                                          */
-                                    }
-                                    println!("    token_uuid: {:?}", &token_uuid);
-                                    println!("    leading_ws: {:?}", &leading_ws);
-                                    /*
-                                    match leading_ws {
-                                        edit::Edit::Copy(x) => println!("    copy: ws {:?}", &x),
-                                        edit::Edit::Change((left_ws, right_ws)) => {
-                                            println!("    value: {} => {}", left_ws, right_ws)
-                                        }
-                                    }
-                                    */
-                                }
-                            };
-                        })
-                        .collect::<Vec<_>>();
-                }
-            };
+        match record.level() {
+            Level::Error => eprintln!("{} {}", paint(Colour::Red, "error:"), record.args()),
+            Level::Warn => eprintln!("{} {}", paint(Colour::Yellow, "warning:"), record.args()),
+            _ => eprintln!("{}", record.args()),
         }
     }
+
+    fn flush(&self) {}
 }
 
-fn atom2str(atom: &TextAtom) -> String {
-    format!("{}{}", atom.leading_ws, atom.token_value)
+fn init_logging(opts: &Opts) {
+    let level = match (opts.quiet, opts.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let color = opts.color.unwrap_or_default();
+    STDERR_COLOR.store(
+        color.enabled(std::io::stderr().is_terminal()),
+        Ordering::Relaxed,
+    );
+    static LOGGER: StderrLogger = StderrLogger;
+    log::set_logger(&LOGGER).expect("logger already set");
+    log::set_max_level(level);
 }
 
-fn print_diff_c<'a>(right: &ParseStruct, diff: diffus::edit::Edit<'a, ParseStruct>) {
-    let mut atom_index = 0;
-    match diff {
-        edit::Edit::Copy(x) => {
-            println!("Identical parses: {:#?}", &x);
+/// Read the file, or stdin if there is no name, giving up on errors
+fn read_input(fname: Option<&str>) -> String {
+    let result = match fname {
+        Some(fname) => std::fs::read_to_string(fname),
+        None => {
+            use std::io::{self, Read};
+            let mut buffer = String::new();
+            io::stdin().read_to_string(&mut buffer).map(|_| buffer)
         }
-        edit::Edit::Change(EditedParseStruct { atoms }) => {
-            let diff = atoms;
-            match diff {
-                edit::Edit::Copy(x) => {
-                    x.into_iter().map(|xx| {
-                        print!("{}", &atom2str(&xx));
-                        atom_index = atom_index + 1;
-                    });
-                }
-                edit::Edit::Change(diff) => {
-                    diff.into_iter()
-                        .map(|edit| {
-                            match edit {
-                                collection::Edit::Copy(elem) => {
-                                    print!("{}", &atom2str(&elem));
-                                    atom_index = atom_index + 1;
-                                }
-                                collection::Edit::Insert(elem) => {
-                                    print!("{}", Colour::Green.paint(atom2str(&elem)));
-                                    atom_index = atom_index + 1;
-                                }
-                                collection::Edit::Remove(elem) => {
-                                    print!("{}", Colour::Red.paint(atom2str(&elem)));
-                                }
-                                collection::Edit::Change(EditedTextAtom {
-                                    token_value,
-                                    token_uuid,
-                                    leading_ws,
-                                }) => {
-                                    match token_value {
-                                        edit::Edit::Copy(x) => {
-                                            let ws = &right.atoms[atom_index].leading_ws;
-                                            let tok = &right.atoms[atom_index].token_value;
-                                            print!(
-                                                "{}",
-                                                Colour::Purple.paint(format!("{}{}", ws, &x))
-                                            );
-                                        }
-                                        x => {
-                                            println!("    changed: id {:?}", &x);
-                                        } /*
-                                          edit::Edit::Change((left_id, right_id)) => {
-                                              println!("    token_value: {} => {}", left_id, right_id)
-                                          }
-                                          */
-                                    }
-                                    atom_index = atom_index + 1;
-                                    /*
-                                    println!("    token_uuid: {:?}", &token_uuid);
-                                    println!("    leading_ws: {:?}", &leading_ws);
-                                    */
-                                    /*
-                                    match leading_ws {
-                                        edit::Edit::Copy(x) => println!("    copy: ws {:?}", &x),
-                                        edit::Edit::Change((left_ws, right_ws)) => {
-                                            println!("    value: {} => {}", left_ws, right_ws)
-                                        }
-                                    }
-                                    */
-                                }
-                            };
-                        })
-                        .collect::<Vec<_>>();
-                }
-            };
+    };
+    result.unwrap_or_else(|e| {
+        error!("{}: {}", fname.unwrap_or("stdin"), e);
+        std::process::exit(2);
+    })
+}
+
+fn read_patchset(fname: Option<&str>) -> PatchSet {
+    PatchSet::parse(&read_input(fname)).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(2);
+    })
+}
+
+fn parse_file(settings: &Settings, fname: &str) -> ParseStruct {
+    let tokenizer = settings.patcher_for(fname).options().tokenizer;
+    parse_string_with(&read_input(Some(fname)), tokenizer)
+}
+
+/// The files under the directory, relative to it
+fn list_files(dir: &Path, prefix: &Path, files: &mut BTreeSet<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("{}: {}", dir.display(), e);
+            std::process::exit(2);
         }
+    };
+    for entry in entries.flatten() {
+        let rel_path = prefix.join(entry.file_name());
+        if entry.path().is_dir() {
+            list_files(&entry.path(), &rel_path, files);
+        } else {
+            files.insert(rel_path);
+        }
+    }
+}
+
+/// Returns whether the files are the same
+fn diff_two_files(
+    settings: &Settings,
+    args: &DiffArgs,
+    old: Option<&str>,
+    new: Option<&str>,
+) -> bool {
+    let read = |fname: Option<&str>| match fname {
+        Some(fname) => std::fs::read_to_string(fname).map_err(|e| error!("{}: {}", fname, e)),
+        None => Ok(String::new()),
+    };
+    let (old_text, new_text) = match (read(old), read(new)) {
+        (Ok(old_text), Ok(new_text)) => (old_text, new_text),
+        _ => return false,
+    };
+    let name = new.or(old).unwrap();
+    if let Some(diff) = settings.word_diff(name, &old_text, &new_text, args.unified) {
+        if !diff.is_empty() {
+            println!(
+                "--- {}\n+++ {}",
+                old.unwrap_or(DEV_NULL),
+                new.unwrap_or(DEV_NULL)
+            );
+            print!("{}", diff);
+        }
+        return diff.is_empty();
+    }
+    let tokenizer = settings.patcher_for(name).options().tokenizer;
+    let diff = unified_diff(
+        &old_text,
+        &new_text,
+        old.unwrap_or(DEV_NULL),
+        new.unwrap_or(DEV_NULL),
+        args.unified,
+        tokenizer,
+    );
+    print!("{}", diff);
+    diff.is_empty()
+}
+
+/// Returns whether there are no differences
+fn diff_files(settings: &Settings, args: &DiffArgs) -> bool {
+    if !args.recursive {
+        return diff_two_files(settings, args, Some(&args.old_fname), Some(&args.new_fname));
+    }
+    let (old_dir, new_dir) = (Path::new(&args.old_fname), Path::new(&args.new_fname));
+    let mut old_files = BTreeSet::new();
+    let mut new_files = BTreeSet::new();
+    list_files(old_dir, Path::new(""), &mut old_files);
+    list_files(new_dir, Path::new(""), &mut new_files);
+    let mut same = true;
+    for rel_path in old_files.union(&new_files) {
+        let old_path = old_dir.join(rel_path);
+        let new_path = new_dir.join(rel_path);
+        let old = Some(old_path.to_str().unwrap()).filter(|_| old_files.contains(rel_path));
+        let new = Some(new_path.to_str().unwrap()).filter(|_| new_files.contains(rel_path));
+        same &= diff_two_files(settings, args, old, new);
+    }
+    same
+}
+
+/// Returns whether there are no conflicts
+fn merge_files(settings: &Settings, args: &MergeArgs) -> bool {
+    let opts = MergeOptions {
+        tokenizer: settings.patcher_for(&args.ours_fname).options().tokenizer,
+        style: if args.diff3 {
+            MergeStyle::Diff3
+        } else {
+            MergeStyle::Merge
+        },
+        labels: [
+            Some(args.ours_fname.clone()),
+            Some(args.base_fname.clone()),
+            Some(args.theirs_fname.clone()),
+        ],
+        ..Default::default()
+    };
+    let merged = merge3(
+        &read_input(Some(&args.base_fname)),
+        &read_input(Some(&args.ours_fname)),
+        &read_input(Some(&args.theirs_fname)),
+        &opts,
+    );
+    print!("{}", merged.text);
+    if merged.conflicts > 0 {
+        warn!(
+            "{} conflict{}",
+            merged.conflicts,
+            if merged.conflicts == 1 { "" } else { "s" }
+        );
+    }
+    merged.conflicts == 0
+}
+
+/*
+ * Git wants the result in the file of our version, whether or not
+ * there are conflicts, and the exit status telling which it was.
+ */
+fn merge_driver(settings: &Settings, args: &MergeDriverArgs) -> bool {
+    let path = args.path.as_deref().unwrap_or(&args.ours_fname);
+    let opts = MergeOptions {
+        tokenizer: settings.patcher_for(path).options().tokenizer,
+        style: if args.diff3 {
+            MergeStyle::Diff3
+        } else {
+            MergeStyle::Merge
+        },
+        marker_size: args.marker_size.unwrap_or(7),
+        labels: [
+            Some(format!("ours:{}", path)),
+            Some(format!("base:{}", path)),
+            Some(format!("theirs:{}", path)),
+        ],
+    };
+    let merged = merge3(
+        &read_input(Some(&args.base_fname)),
+        &read_input(Some(&args.ours_fname)),
+        &read_input(Some(&args.theirs_fname)),
+        &opts,
+    );
+    if let Err(e) = std::fs::write(&args.ours_fname, &merged.text) {
+        error!("{}: {}", args.ours_fname, e);
+        std::process::exit(2);
+    }
+    if merged.conflicts > 0 {
+        warn!(
+            "{}: {} conflict{}",
+            path,
+            merged.conflicts,
+            if merged.conflicts == 1 { "" } else { "s" }
+        );
     }
-    println!("");
+    merged.conflicts == 0
 }
 
-fn test_diffus() {
-    let left = parse_nth_arg(1);
-    let right = parse_nth_arg(2);
+/// The text of the file, None if it is not text
+fn read_text(fname: &str) -> Option<String> {
+    if fname == DEV_NULL {
+        return Some(String::new());
+    }
+    match std::fs::read(fname) {
+        Ok(bytes) => String::from_utf8(bytes).ok(),
+        Err(e) => {
+            error!("{}: {}", fname, e);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// The header, the summary of the changes, and the changes token by token
+fn show_token_changes(settings: &Settings, header: &str, path: &str, old: &str, new: &str) {
+    print!("{}", header);
+    let (old, new) = match (read_text(old), read_text(new)) {
+        (Some(old), Some(new)) => (old, new),
+        _ => {
+            println!("Binary files differ");
+            return;
+        }
+    };
+    let tokenizer = settings.patcher_for(path).options().tokenizer;
+    println!("{}", WordDiffStats::new(&old, &new, tokenizer));
+    print!("{}", settings.render_word_diff(path, &old, &new, 3));
+}
 
-    let diff = left.diff(&right);
-    print_diff(diff);
+/*
+ * Git runs it with the seven arguments for each file changed,
+ * two more for the renames, and just the path for the unmerged ones.
+ */
+fn external_diff(settings: &Settings, args: &ExternalDiffArgs) -> bool {
+    let a = &args.args;
+    if a.len() < 7 {
+        println!("* Unmerged path {}", a[0]);
+        return true;
+    }
+    let (path, old_file, old_mode, new_file, new_mode) = (&a[0], &a[1], &a[3], &a[4], &a[6]);
+    let new_path = a.get(7).unwrap_or(path);
+    let mut header = format!("diff --tbpatch a/{} b/{}\n", path, new_path);
+    if new_path != path {
+        header.push_str(&format!("rename from {}\nrename to {}\n", path, new_path));
+    }
+    if old_file == DEV_NULL {
+        header.push_str(&format!("new file mode {}\n", new_mode));
+    } else if new_file == DEV_NULL {
+        header.push_str(&format!("deleted file mode {}\n", old_mode));
+    } else if old_mode != new_mode {
+        header.push_str(&format!("old mode {}\nnew mode {}\n", old_mode, new_mode));
+    }
+    show_token_changes(settings, &header, new_path, old_file, new_file);
+    true
 }
 
-fn join_lines(lines: &Vec<unidiff::Line>) -> String {
-    format!(
-        "\n{}",
-        lines
-            .into_iter()
-            .map(|x| x.value.clone())
-            .collect::<Vec<String>>()
-            .join("\n")
-    )
+fn difftool(settings: &Settings, args: &DifftoolArgs) -> bool {
+    let path = args
+        .path
+        .clone()
+        .or_else(|| std::env::var("MERGED").ok())
+        .unwrap_or_else(|| args.remote_fname.clone());
+    let header = format!("diff --tbpatch a/{} b/{}\n", path, path);
+    show_token_changes(
+        settings,
+        &header,
+        &path,
+        &args.local_fname,
+        &args.remote_fname,
+    );
+    true
 }
 
-enum HunkOp {
-    ShowDiff,
-    Patch(usize),
+fn dump_tokens(settings: &Settings, file: &OneFile) {
+    let parse = parse_file(settings, &file.fname);
+    for atom in &parse.atoms {
+        println!("{:?} {:?}", atom.leading_ws, atom.token_value);
+    }
 }
 
-pub fn find_needle<N, H>(needle: &[N], haystack: &[H], debug: bool) -> Option<usize>
-where
-    N: PartialEq + std::cmp::PartialEq<H> + std::fmt::Debug,
-    H: PartialEq<N> + std::fmt::Debug,
-{
-    for (hi, ch) in haystack.into_iter().enumerate() {
-        let mut match_count = 0;
-        for (ni, cn) in needle.into_iter().enumerate() {
-            if debug {
-                println!("pos {}  : {:?} / {:?}", ni, &cn, &haystack[hi + ni]);
+/// Returns whether all the hunks apply
+fn explain(settings: &Settings, diff_fname: Option<&str>) -> bool {
+    let patch = read_patchset(diff_fname);
+    let mut all_applied = true;
+    for file in patch.files() {
+        let path = match file.source_path(settings.options.strip) {
+            Ok(Some(path)) => path,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("{}", e);
+                all_applied = false;
+                continue;
             }
-            if hi + ni < haystack.len() && cn == &haystack[hi + ni] {
-                match_count = match_count + 1;
-            } else {
-                break;
+        };
+        let patcher = settings.patcher_for(&path);
+        let original = match DiskFileSystem.read_to_string(&path) {
+            Ok(original) => original,
+            Err(e) => {
+                println!("{}: {}", path, e);
+                all_applied = false;
+                continue;
             }
-            if debug {
-                println!("    {:?}  vs  {:?}", &cn, &haystack[hi + ni]);
+        };
+        let applied = patcher.try_apply_hunks(&original, file.hunks());
+        for (i, (hunk, result)) in file.hunks().iter().zip(&applied.hunks).enumerate() {
+            print!("{}: hunk #{}: {}", path, i + 1, result.status.as_str());
+            if result.status.is_applied() {
+                println!(" at line {} (offset {})", result.line, result.offset);
+                continue;
             }
-        }
-        if match_count > needle.len() / 2 || debug {
-            if debug {
-                println!("   matches: {} / {}", match_count, needle.len());
+            all_applied = false;
+            match find_mismatch(&applied.text, hunk, patcher.options().tokenizer) {
+                Some(m) => println!(
+                    ", {} of {} context tokens match at line {}, then {:?} (line {} of the diff) is {}",
+                    m.matched,
+                    m.total,
+                    m.line,
+                    m.expected,
+                    m.hunk_line,
+                    m.found
+                        .map_or("past the end of the file".to_string(), |f| format!(
+                            "{:?} in the file",
+                            f
+                        ))
+                ),
+                None => println!(" at line {}", result.line),
             }
         }
+    }
+    all_applied
+}
+
+/*
+ * The hunks are shown one by one on stderr, and the answers read from
+ * stdin, the way "git add -p" does it.
+ */
+struct HunkPrompt {
+    /// whether the rest of the hunks are all skipped
+    quit: bool,
+}
+
+const HUNK_PROMPT_HELP: &str = "\
+y - apply the hunk at the place shown
+n - skip the hunk
+e - edit the hunk, and ask again
+q - skip the hunk and all the ones after it
+1-9... - show the place with this number, when the context is in more than one
+? - print this help";
 
-        if match_count == needle.len() {
-            return Some(hi);
+impl HunkPrompt {
+    fn ask(
+        &mut self,
+        path: &str,
+        hunk_no: usize,
+        hunk: &Hunk,
+        candidates: &[HunkCandidate],
+    ) -> HunkChoice {
+        let mut shown = 0;
+        let mut show = true;
+        while !self.quit {
+            if show {
+                eprintln!(
+                    "{}",
+                    paint(Colour::Cyan, &format!("{} hunk #{}", path, hunk_no + 1))
+                );
+                match candidates.get(shown) {
+                    Some(candidate) => {
+                        let preview = candidate.preview(STDERR_COLOR.load(Ordering::Relaxed));
+                        eprintln!("{}", preview.trim_matches('\n'))
+                    }
+                    None => eprintln!("{}", **hunk),
+                }
+                show = false;
+            }
+            let answers = match candidates.len() {
+                0 => "n,e,q,?".to_string(),
+                1 => "y,n,e,q,?".to_string(),
+                n => format!("y,n,e,q,1-{},?", n),
+            };
+            match candidates.get(shown) {
+                Some(candidate) => eprint!(
+                    "{} at line {} (offset {}){} [{}]? ",
+                    paint(Colour::Blue, "Apply this hunk"),
+                    candidate.applied.line,
+                    candidate.applied.offset,
+                    match candidates.len() {
+                        1 => String::new(),
+                        n => format!(", place {} of {}", shown + 1, n),
+                    },
+                    answers
+                ),
+                None => eprint!(
+                    "{} [{}]? ",
+                    paint(
+                        Colour::Blue,
+                        "The context is not in the file, skip this hunk"
+                    ),
+                    answers
+                ),
+            }
+            let mut answer = String::new();
+            match std::io::stdin().read_line(&mut answer) {
+                Ok(0) | Err(_) => {
+                    eprintln!();
+                    self.quit = true;
+                }
+                Ok(_) => match answer.trim() {
+                    "y" if !candidates.is_empty() => return HunkChoice::Apply(shown),
+                    "n" => return HunkChoice::Skip,
+                    "q" => self.quit = true,
+                    "e" => {
+                        if let Some(edited) = edit_hunk(path, hunk) {
+                            return HunkChoice::Edit(edited);
+                        }
+                    }
+                    answer => match answer.parse::<usize>() {
+                        Ok(n) if (1..=candidates.len()).contains(&n) => {
+                            shown = n - 1;
+                            show = true;
+                        }
+                        _ => eprintln!("{}", paint(Colour::Red, HUNK_PROMPT_HELP)),
+                    },
+                },
+            }
         }
+        HunkChoice::Skip
     }
-    None
 }
 
-fn get_truncated_file_name(fname: &str, p: usize) -> String {
-    let path = std::path::Path::new(&fname);
-    let mut comp = path.components();
-    // I can't do this: let path = path.components().skip(p).as_path();
-    // So I will do this:
-    for i in 0..p {
-        comp.next();
+/// The hunk as edited in $GIT_EDITOR, $VISUAL or $EDITOR, None if it cannot be used
+fn edit_hunk(path: &str, hunk: &Hunk) -> Option<Hunk> {
+    /* created anew with a name nobody can guess, and removed when dropped */
+    let file = tempfile::Builder::new()
+        .prefix("tbpatch-hunk-")
+        .suffix(".diff")
+        .tempfile();
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            error!("{}", e);
+            return None;
+        }
+    };
+    let fname = file.path().to_path_buf();
+    let text = format!(
+        "# Edit the hunk for {}.\n\
+         # To drop a '-' line, make it a ' ' line; to drop a '+' line, delete it.\n\
+         # The lines starting with # are removed, and the line counts are redone.\n\
+         {}\n",
+        path, **hunk
+    );
+    if let Err(e) = file.write_all(text.as_bytes()) {
+        error!("{}: {}", fname.display(), e);
+        return None;
     }
-    let path = comp.as_path();
-    path.to_str().unwrap().to_string()
+    let editor = ["GIT_EDITOR", "VISUAL", "EDITOR"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|editor| !editor.is_empty()))
+        .unwrap_or_else(|| "vi".to_string());
+    /* the editor may come with arguments */
+    let status = std::process::Command::new("sh")
+        .args(["-c", &format!("{} \"$@\"", editor), &editor])
+        .arg(&fname)
+        .status();
+    let edited = match status {
+        Ok(status) if status.success() => read_text(&fname.to_string_lossy()),
+        Ok(status) => {
+            error!("{}: {}", editor, status);
+            None
+        }
+        Err(e) => {
+            error!("{}: {}", editor, e);
+            None
+        }
+    };
+    drop(file);
+    let edited = parse_edited_hunk(hunk, &edited?);
+    if edited.is_none() {
+        error!("the edited hunk does not parse, it is left as it was");
+    }
+    edited
 }
 
-fn parse_patched_file(file: &unidiff::PatchedFile, p: usize) -> ParseStruct {
-    let src_path = get_truncated_file_name(&file.source_file, p);
-
-    println!("src path: {}", &src_path);
-    parse_file(&src_path)
+/// The hunk from the edited text, with the line counts of its header redone
+fn parse_edited_hunk(hunk: &Hunk, text: &str) -> Option<Hunk> {
+    let mut body = String::new();
+    let (mut source, mut target) = (0, 0);
+    for line in text.lines() {
+        if line.starts_with('#') || line.starts_with("@@") {
+            continue;
+        }
+        /* the editors may strip the space of the empty context lines */
+        let line = if line.is_empty() { " " } else { line };
+        match line.chars().next() {
+            Some(' ') => {
+                source += 1;
+                target += 1;
+            }
+            Some('-') => source += 1,
+            Some('+') => target += 1,
+            Some('\\') => {}
+            _ => return None,
+        }
+        body.push_str(line);
+        body.push('\n');
+    }
+    let diff = format!(
+        "--- a\n+++ b\n@@ -{},{} +{},{} @@ {}\n{}",
+        hunk.source_start, source, hunk.target_start, target, hunk.section_header, body
+    );
+    let patch = PatchSet::parse(&diff).ok()?;
+    patch.files().first()?.hunks().first().cloned()
 }
 
-fn apply_patch<'a>(
-    out_file: &mut ParseStruct,
-    src_file: &ParseStruct,
-    p: usize,
-    right: &ParseStruct,
-    diff: diffus::edit::Edit<'a, ParseStruct>,
-) -> usize {
-    let mut atom_index = 0;
-    let mut src_skip = 0;
-
-    match diff {
-        edit::Edit::Copy(x) => {
-            for atom in &right.atoms {
-                assert!(&src_file.atoms[p + src_skip] == atom);
-                out_file.atoms.push(atom.clone());
-                src_skip = src_skip + 1;
+/// Returns whether all the files have been patched
+fn apply(
+    opts: &Opts,
+    settings: &Settings,
+    patch: &PatchSet,
+    fs: &dyn FileSystem,
+    verb: &str,
+    report: &mut Report,
+) -> bool {
+    let strip = settings.options.strip;
+    let mut html_report = HtmlReport::default();
+    let mut refreshed = String::new();
+    let mut all_applied = true;
+    let no_hunks = vec![];
+    let mut prompt = HunkPrompt { quit: false };
+    let blobs = opts.three_way.then(|| {
+        BlobStore::discover(Path::new(".")).unwrap_or_else(|e| {
+            error!("{}", e.message());
+            std::process::exit(2);
+        })
+    });
+    for file in patch.files() {
+        debug!("{}", paint(Colour::Cyan, "==================="));
+        debug!("{} {}", paint(Colour::Cyan, "==="), file.source_file);
+        debug!("{} {}", paint(Colour::Cyan, "==="), file.target_file);
+        for hunk in file.hunks() {
+            debug!("{} {}", paint(Colour::Cyan, "==="), hunk.section_header);
+            debug!(
+                "{} {:+0} lines [ {}[{}] {}[{}] ] =>\n",
+                paint(Colour::Cyan, "==="),
+                hunk.added() as i64 - hunk.removed() as i64,
+                hunk.source_start,
+                hunk.source_length,
+                hunk.target_start,
+                hunk.target_length
+            );
+        }
+        let paths = file
+            .source_path(strip)
+            .and_then(|src_path| Ok((src_path, file.target_path(strip)?)));
+        let (src_path, dst_path) = match paths {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("{}", e);
+                all_applied = false;
+                report.push(file, &Err(e));
+                continue;
+            }
+        };
+        match (&src_path, &dst_path) {
+            (Some(src), Some(dst)) if file.renamed => {
+                info!("{} file {} (renamed from {})", verb, dst, src)
             }
+            _ => info!(
+                "{} file {}",
+                verb,
+                dst_path.as_ref().or(src_path.as_ref()).unwrap()
+            ),
         }
-        edit::Edit::Change(EditedParseStruct { atoms }) => {
-            let diff = atoms;
-            match diff {
-                edit::Edit::Copy(x) => {
-                    x.into_iter().map(|xx| {
-                        assert!(&src_file.atoms[p + src_skip] == xx);
-                        out_file.atoms.push(xx.clone());
-                        src_skip = src_skip + 1;
-                    });
+        let path = dst_path.as_ref().or(src_path.as_ref()).unwrap();
+        let patcher = settings.patcher_for(path);
+        /* for the word diff, nothing for the files being created */
+        let original = src_path
+            .as_ref()
+            .and_then(|src| fs.read_to_string(src).ok());
+        let result = match &blobs {
+            Some(blobs) => patcher.apply_file_patch_3way(file, fs, |id| {
+                let base = blobs.read(id);
+                match &base {
+                    Some(_) => info!(
+                        "Falling back to three-way merge with the original {}...",
+                        id
+                    ),
+                    None => warn!(
+                        "The original {} is not in the repository, no three-way merge.",
+                        id
+                    ),
                 }
-                edit::Edit::Change(diff) => {
-                    diff.into_iter()
-                        .map(|edit| {
-                            match edit {
-                                collection::Edit::Copy(elem) => {
-                                    assert!(&src_file.atoms[p + src_skip] == elem);
-                                    out_file.atoms.push(elem.clone());
-                                    atom_index = atom_index + 1;
-                                    src_skip = src_skip + 1;
-                                }
-                                collection::Edit::Insert(elem) => {
-                                    out_file.atoms.push(elem.clone());
-                                    atom_index = atom_index + 1;
-                                }
-                                collection::Edit::Remove(elem) => {
-                                    /* do not push out_file.atoms.push(elem.clone()); */
-                                    assert!(&src_file.atoms[p + src_skip] == elem);
-                                    src_skip = src_skip + 1;
-                                }
-                                collection::Edit::Change(EditedTextAtom {
-                                    token_value,
-                                    token_uuid,
-                                    leading_ws,
-                                }) => {
-                                    match token_value {
-                                        edit::Edit::Copy(x) => {
-                                            let ws = &right.atoms[atom_index].leading_ws;
-                                            let aid = &right.atoms[atom_index].token_uuid;
-                                            let tok = &right.atoms[atom_index].token_value;
-                                            let atom = TextAtom {
-                                                token_value: tok.to_string(),
-                                                token_uuid: aid.to_string(),
-                                                leading_ws: ws.to_string(),
-                                            };
-                                            assert!(&src_file.atoms[p + src_skip] == &atom);
-                                            out_file.atoms.push(atom);
-                                        }
-                                        x => {
-                                            println!("    changed: id {:?}", &x);
-                                            panic!("Editing the changed IDs is not supported");
-                                        } /*
-                                          edit::Edit::Change((left_id, right_id)) => {
-                                              println!("    token_value: {} => {}", left_id, right_id)
-                                          }
-                                          */
-                                    }
-                                    atom_index = atom_index + 1;
-                                    src_skip = src_skip + 1;
-                                    /*
-                                    println!("    token_uuid: {:?}", &token_uuid);
-                                    println!("    leading_ws: {:?}", &leading_ws);
-                                    */
-                                    /*
-                                    match leading_ws {
-                                        edit::Edit::Copy(x) => println!("    copy: ws {:?}", &x),
-                                        edit::Edit::Change((left_ws, right_ws)) => {
-                                            println!("    value: {} => {}", left_ws, right_ws)
-                                        }
-                                    }
-                                    */
-                                }
-                            };
-                        })
-                        .collect::<Vec<_>>();
+                base
+            }),
+            None if opts.interactive => {
+                patcher.apply_file_patch_interactive(file, fs, |i, hunk, candidates| {
+                    prompt.ask(path, i, hunk, candidates)
+                })
+            }
+            None => patcher.apply_file_patch(file, fs),
+        };
+        if let Ok(applied) = &result {
+            let old = original.unwrap_or_default();
+            refreshed.push_str(&refreshed_file_patch(
+                &old,
+                applied,
+                file.old_mode,
+                file.new_mode,
+                3,
+                patcher.options().tokenizer,
+            ));
+            let diff = settings.word_diff(path, &old, &applied.applied.text, 3);
+            if let Some(diff) = diff.filter(|diff| !diff.is_empty()) {
+                println!(
+                    "--- {}\n+++ {}",
+                    src_path.as_deref().unwrap_or(DEV_NULL),
+                    dst_path.as_deref().unwrap_or(DEV_NULL)
+                );
+                print!("{}", diff);
+            }
+        }
+        let hunks = match &result {
+            Ok(applied) => &applied.applied.hunks,
+            Err(PatchError::HunksFailed { applied, .. }) => &applied.hunks,
+            Err(e) => {
+                error!("{}", e);
+                &no_hunks
+            }
+        };
+        for (i, hunk) in hunks.iter().enumerate() {
+            match hunk.status {
+                HunkStatus::Failed => error!("Hunk #{} FAILED at {}.", i + 1, hunk.line),
+                HunkStatus::Conflict => warn!(
+                    "Hunk #{} NOT MERGED at {}-{}.",
+                    i + 1,
+                    hunk.line,
+                    hunk.end_line
+                ),
+                HunkStatus::AlreadyApplied => {
+                    warn!("Hunk #{} already applied at {}.", i + 1, hunk.line)
                 }
-            };
+                /* the lines are of the original */
+                HunkStatus::Merged => {}
+                _ if hunk.offset != 0 => info!(
+                    "Hunk #{} succeeded at {} (offset {} line{}).",
+                    i + 1,
+                    hunk.line,
+                    hunk.offset,
+                    if hunk.offset.abs() == 1 { "" } else { "s" }
+                ),
+                _ => {}
+            }
+        }
+        if let Err(PatchError::HunksFailed { applied, path }) = &result {
+            let failed = applied.hunks.iter().filter(|h| !h.status.is_applied());
+            error!(
+                "{} out of {} hunks FAILED -- {} left unchanged",
+                failed.count(),
+                applied.hunks.len(),
+                path
+            );
+        }
+        /* the conflicts are left to be resolved */
+        all_applied &= result.is_ok() && hunks.iter().all(|h| h.status != HunkStatus::Conflict);
+        report.push(file, &result);
+        html_report.push(file, &result, patcher.options().tokenizer);
+    }
+    if opts.report == Some(ReportFormat::Json) {
+        println!("{}", report.to_json());
+    }
+    let outputs = [
+        (&opts.html_report, html_report.to_html()),
+        (&opts.emit_refreshed_patch, refreshed),
+    ];
+    for (fname, contents) in &outputs {
+        if let Some(fname) = fname {
+            if let Err(e) = std::fs::write(fname, contents) {
+                error!("{}: {}", fname, e);
+                return false;
+            }
+        }
+    }
+    all_applied
+}
+
+/// The files of the directory, or of the tree of the git revision
+fn open_tree(spec: &str) -> Box<dyn FileSystem> {
+    if Path::new(spec).is_dir() {
+        return Box::new(DirFileSystem::new(Path::new(spec)));
+    }
+    match TreeFileSystem::discover(Path::new("."), spec) {
+        Ok(tree) => Box::new(tree),
+        Err(e) => {
+            error!("{}: {}", spec, e.message());
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Returns whether all the files have been carried over without conflicts
+fn rebase_patch(settings: &Settings, args: &RebasePatchArgs) -> bool {
+    let patch = read_patchset(args.diff_fname.as_deref());
+    let (old_tree, new_tree) = (open_tree(&args.old_tree), open_tree(&args.new_tree));
+    let mut all_rebased = true;
+    for file in patch.files() {
+        let strip = settings.options.strip;
+        let path = file
+            .target_path(strip)
+            .and_then(|dst_path| Ok(dst_path.or(file.source_path(strip)?)))
+            .unwrap_or_default()
+            .unwrap_or_default();
+        info!("rebasing file {}", path);
+        let patcher = settings.patcher_for(&path);
+        match rebase_file_patch(&patcher, file, old_tree.as_ref(), new_tree.as_ref()) {
+            Ok(rebased) => {
+                if rebased.conflicts > 0 {
+                    warn!(
+                        "{}: the trees differ in more than the formatting, {} conflict{}",
+                        path,
+                        rebased.conflicts,
+                        if rebased.conflicts == 1 { "" } else { "s" }
+                    );
+                    all_rebased = false;
+                }
+                print!("{}", rebased.diff);
+            }
+            Err(e) => {
+                error!("{}", e);
+                all_rebased = false;
+            }
         }
     }
-    return src_skip;
+    all_rebased
+}
+
+/*
+ * "am" keeps the mails, and which of them is next, in the .git directory
+ * between the runs, so that it can be continued after a patch fails.
+ */
+struct AmState {
+    dir: PathBuf,
 }
 
-fn do_patch(
-    src_file: &ParseStruct,
-    file: &unidiff::PatchedFile,
-    hunk: &unidiff::Hunk,
-) -> ParseStruct {
-    let src = join_lines(&hunk.source_lines())
-        .to_string()
-        .trim_end_matches(char::is_whitespace)
-        .to_string();
-    let src = parse_string(&src);
+impl AmState {
+    fn read(&self, name: &str) -> String {
+        read_input(Some(&self.dir.join(name).to_string_lossy()))
+    }
+
+    fn write(&self, name: &str, contents: &str) {
+        if let Err(e) = std::fs::write(self.dir.join(name), contents) {
+            error!("{}: {}", self.dir.join(name).display(), e);
+            std::process::exit(2);
+        }
+    }
 
-    let dst = join_lines(&hunk.target_lines())
-        .to_string()
-        .trim_end_matches(char::is_whitespace)
-        .to_string();
-    let dst = parse_string(&dst);
+    fn number(&self, name: &str) -> usize {
+        self.read(name).trim().parse().unwrap_or_else(|_| {
+            error!("{}: corrupt, use --abort", self.dir.join(name).display());
+            std::process::exit(2);
+        })
+    }
 
-    let diff = src.diff(&dst);
-    print_diff_c(&dst, diff);
-    let diff = src.diff(&dst);
-    print_diff(diff);
-    let diff = src.diff(&dst);
+    fn mail(&self, n: usize) -> Mail {
+        Mail::parse(&self.read(&format!("{:04}", n)))
+    }
+}
 
-    let find_pos = if src_file.atoms.len() == 0  && src.atoms.len() == 0 {
-        Some(0)
-    } else {
-        find_needle(&src.atoms, &src_file.atoms, false)
+/// Apply the mails from the next one on, stopping at the first which fails
+fn am_run(opts: &Opts, settings: &Settings, fs: &GitFileSystem, state: &AmState) -> bool {
+    let last = state.number("last");
+    for n in state.number("next")..=last {
+        let mail = state.mail(n);
+        info!("Applying: {}", mail.subject);
+        let applied = match PatchSet::parse(&mail.diff) {
+            Ok(patch) if !patch.files().is_empty() => apply(
+                opts,
+                settings,
+                &patch,
+                fs,
+                "patching",
+                &mut Report::default(),
+            ),
+            Ok(_) => {
+                error!("Patch is empty.");
+                false
+            }
+            Err(e) => {
+                error!("{}", e);
+                false
+            }
+        };
+        /* the files patched go to the index even if some failed, to be fixed up */
+        if let Err(e) = fs.write_index() {
+            error!("{}", e.message());
+        }
+        let committed = applied
+            && fs
+                .commit_mail(&mail)
+                .map_err(|e| error!("{}", e.message()))
+                .is_ok();
+        if !committed {
+            error!("Patch failed at {:04} {}", n, mail.subject);
+            info!(
+                "Fix it and \"git add\" the files, then run \"tbpatch am --continue\"; \
+                 or \"tbpatch am --skip\" to drop it, \"tbpatch am --abort\" to go back."
+            );
+            return false;
+        }
+        state.write("next", &format!("{}\n", n + 1));
+    }
+    if let Err(e) = std::fs::remove_dir_all(&state.dir) {
+        error!("{}: {}", state.dir.display(), e);
+    }
+    true
+}
+
+fn am(opts: &Opts, settings: &Settings, args: &AmArgs) -> bool {
+    let fs = open_git(GitTarget::Index);
+    let state = AmState {
+        dir: fs.git_dir().join("tbpatch-am"),
     };
-    println!("FindPos: {:?} (of {})", &find_pos, src.atoms.len());
-    if let Some(p) = find_pos {
-        let mut out_file = ParseStruct {
-            atoms: src_file.atoms[0..p].to_vec(),
+    let in_progress = state.dir.exists();
+    if !in_progress && (args.resume || args.skip || args.abort) {
+        error!("no \"tbpatch am\" in progress");
+        return false;
+    }
+    if args.abort {
+        if let Err(e) = fs.reset_hard(state.read("orig-head").trim()) {
+            error!("{}", e.message());
+            return false;
+        }
+        return std::fs::remove_dir_all(&state.dir).is_ok();
+    }
+    if args.resume || args.skip {
+        let next = state.number("next");
+        let result = if args.skip {
+            fs.reset_hard(&fs.head().unwrap_or_default())
+        } else {
+            fs.commit_mail(&state.mail(next)).map(|_| ())
         };
-        let src_skip = apply_patch(&mut out_file, src_file, p, &dst, diff);
-        for atom in &src_file.atoms[p + src_skip..] {
-            out_file.atoms.push(atom.clone());
+        if let Err(e) = result {
+            error!("{}", e.message());
+            return false;
         }
-        return out_file;
+        state.write("next", &format!("{}\n", next + 1));
+        return am_run(opts, settings, &fs, &state);
+    }
+    if in_progress {
+        error!(
+            "{} exists, a \"tbpatch am\" is in progress: use --continue, --skip or --abort",
+            state.dir.display()
+        );
+        return false;
+    }
+    let head = fs.head().unwrap_or_else(|| {
+        error!("nothing is checked out to apply the patches on");
+        std::process::exit(2);
+    });
+    /* --abort and --skip reset the changes away, the local ones with them */
+    if or_exit(fs.has_changes()) {
+        error!("the index or the work tree has changes: commit or stash them first");
+        return false;
+    }
+    let inputs: Vec<String> = if args.mbox_fnames.is_empty() {
+        vec![read_input(None)]
     } else {
-        // println!("needle: {:?}", &src.atoms);
-        // println!("haystack: {:?}", &src_file.atoms)
-        let find_pos = find_needle(&src.atoms, &src_file.atoms, true);
-        println!("File:'{}'", parse_struct2str(src_file));
-        panic!("Can not find context");
+        args.mbox_fnames
+            .iter()
+            .map(|f| read_input(Some(f)))
+            .collect()
+    };
+    let mails: Vec<String> = inputs.iter().flat_map(|text| split_mbox(text)).collect();
+    if let Err(e) = std::fs::create_dir_all(&state.dir) {
+        error!("{}: {}", state.dir.display(), e);
+        return false;
     }
-
-    return src_file.clone();
+    for (i, mail) in mails.iter().enumerate() {
+        state.write(&format!("{:04}", i + 1), mail);
+    }
+    state.write("orig-head", &format!("{}\n", head));
+    state.write("next", "1\n");
+    state.write("last", &format!("{}\n", mails.len()));
+    am_run(opts, settings, &fs, &state)
 }
 
-fn test_unidiff(opts: &Opts) {
-    let diff_str = if let Some(fname) = &opts.diff_fname {
-        std::fs::read_to_string(fname).unwrap()
-    } else {
-        use std::io::{self, Read};
-        let mut buffer = String::new();
-        io::stdin().read_to_string(&mut buffer).unwrap();
-        buffer
+/*
+ * Each commit of the range is applied as its diff, with the fuzz and the
+ * fallbacks of the options, and committed with its author and message
+ * if it applies; the ones which fail or conflict are left out, and the
+ * series goes on without them.
+ */
+fn backport(opts: &Opts, settings: &Settings, args: &BackportArgs) -> bool {
+    let fs = open_git(GitTarget::Index);
+    /* the commits which fail are reset away, and the local changes with them */
+    if or_exit(fs.has_changes()) {
+        error!("the index or the work tree has changes: commit or stash them first");
+        return false;
+    }
+    let commits = or_exit(fs.commits(&args.range));
+    if let Some(onto) = &args.onto {
+        or_exit(fs.checkout(onto));
+    }
+    /* the reports are of the whole series, not of each diff */
+    let commit_opts = Opts {
+        report: None,
+        html_report: None,
+        emit_refreshed_patch: None,
+        ..opts.clone()
     };
-    let mut patch = unidiff::PatchSet::new();
-    patch.parse(diff_str).ok().expect("Error parsing diff");
-    for file in patch.files() {
-        if opts.verbose > 1 {
-            eprintln!("{}", Colour::Cyan.paint("==================="));
-            eprintln!("{} {}", Colour::Cyan.paint("==="), file.source_file);
-            eprintln!("{} {}", Colour::Cyan.paint("==="), file.target_file);
-        }
-        let mut src_file = parse_patched_file(file, 1);
-        for hunk in file.hunks() {
-            if opts.verbose > 1 {
-                eprintln!("{} {}", Colour::Cyan.paint("==="), hunk.section_header);
-                eprintln!(
-                    "{} {:+0} lines [ {}[{}] {}[{}] ] =>\n",
-                    Colour::Cyan.paint("==="),
-                    hunk.added() as i64 - hunk.removed() as i64,
-                    hunk.source_start,
-                    hunk.source_length,
-                    hunk.target_start,
-                    hunk.target_length
-                );
+    let mut reports = vec![];
+    for id in &commits {
+        let subject = or_exit(fs.commit_summary(id));
+        info!("Applying: {}", subject);
+        let diff = or_exit(fs.commit_diff(id));
+        let mut report = Report::default();
+        let applied = match PatchSet::parse(&diff) {
+            Ok(patch) => apply(&commit_opts, settings, &patch, &fs, "patching", &mut report),
+            Err(e) => {
+                error!("{}", e);
+                false
             }
-            src_file = do_patch(&src_file, file, hunk);
+        };
+        let committed = if applied {
+            fs.write_index()
+                .and_then(|_| fs.commit_as(id))
+                .map_err(|e| error!("{}: {}", subject, e.message()))
+                .ok()
+        } else {
+            None
+        };
+        if committed.is_none() {
+            let head = fs.head().unwrap_or_default();
+            or_exit(fs.reset_hard(&head));
         }
-        let mut out_acc = String::new();
-        for atom in src_file.atoms {
-            out_acc.push_str(&atom2str(&atom));
+        reports.push(CommitReport {
+            commit: id.clone(),
+            subject,
+            outcome: match (applied, &committed) {
+                (true, None) => "failed",
+                _ => report.outcome(),
+            },
+            new_commit: committed,
+            files: report.files,
+        });
+    }
+    if opts.report == Some(ReportFormat::Json) {
+        println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+    } else {
+        for report in &reports {
+            println!(
+                "{} {:<10} {} {}",
+                &report.commit[..7],
+                report.outcome,
+                report.new_commit.as_ref().map_or("-", |id| &id[..7]),
+                report.subject
+            );
         }
-        let src_path = get_truncated_file_name(&file.target_file, 1);
-        let mut src_dir = std::path::PathBuf::from(&src_path);
-        src_dir.pop();
-        std::fs::create_dir_all(src_dir).unwrap();
-        let res = std::fs::write(&src_path, out_acc);
-        if res.is_err() {
-            println!("Error: {:?} writing {}", &res, &src_path)
+    }
+    reports.iter().all(|report| report.new_commit.is_some())
+}
+
+/// Apply to the files on the disk, or in the index of the git repository
+fn apply_to_files(opts: &Opts, settings: &Settings, diff_fname: Option<&str>) -> bool {
+    if opts.interactive && diff_fname.is_none() {
+        error!("--interactive reads the answers from stdin, so the diff has to be in a file");
+        return false;
+    }
+    let patch = read_patchset(diff_fname);
+    let target = match opts.git_target() {
+        Some(target) => target,
+        None => {
+            let mut report = Report::default();
+            return apply(
+                opts,
+                settings,
+                &patch,
+                &DiskFileSystem,
+                "patching",
+                &mut report,
+            );
         }
-        let res = res.unwrap();
+    };
+    let fs = open_git(target);
+    let ok = apply(
+        opts,
+        settings,
+        &patch,
+        &fs,
+        "patching",
+        &mut Report::default(),
+    );
+    if let Err(e) = fs.write_index() {
+        error!("{}", e.message());
+        return false;
     }
+    ok
+}
+
+fn or_exit<T>(result: Result<T, git2::Error>) -> T {
+    result.unwrap_or_else(|e| {
+        error!("{}", e.message());
+        std::process::exit(2);
+    })
+}
+
+fn open_git(target: GitTarget) -> GitFileSystem {
+    GitFileSystem::discover(Path::new("."), target).unwrap_or_else(|e| {
+        error!("{}", e.message());
+        std::process::exit(2);
+    })
 }
 
 fn main() {
     let opts: Opts = Opts::parse();
-    if opts.verbose > 0 {
-        eprintln!("opts: {:#?}", &opts);
+    init_logging(&opts);
+    debug!("opts: {:#?}", &opts);
+    let settings = Settings::new(&opts);
+    let ok = match &opts.subcmd {
+        None => apply_to_files(&opts, &settings, opts.diff_fname.as_deref()),
+        Some(SubCommand::Apply(args)) => {
+            apply_to_files(&opts, &settings, args.diff_fname.as_deref())
+        }
+        Some(SubCommand::Check(args)) => {
+            let git = opts.git_target().map(open_git);
+            let base: &dyn FileSystem = match &git {
+                Some(git) => git,
+                None => &DiskFileSystem,
+            };
+            apply(
+                &opts,
+                &settings,
+                &read_patchset(args.diff_fname.as_deref()),
+                &OverlayFileSystem::new(base),
+                "checking",
+                &mut Report::default(),
+            )
+        }
+        Some(SubCommand::Explain(args)) => explain(&settings, args.diff_fname.as_deref()),
+        Some(SubCommand::Diff(args)) => diff_files(&settings, args),
+        Some(SubCommand::Merge(args)) => merge_files(&settings, args),
+        Some(SubCommand::MergeDriver(args)) => merge_driver(&settings, args),
+        Some(SubCommand::RebasePatch(args)) => rebase_patch(&settings, args),
+        Some(SubCommand::Am(args)) => am(&opts, &settings, args),
+        Some(SubCommand::Backport(args)) => backport(&opts, &settings, args),
+        Some(SubCommand::ExternalDiff(args)) => external_diff(&settings, args),
+        Some(SubCommand::Difftool(args)) => difftool(&settings, args),
+        Some(SubCommand::Tokens(file)) => {
+            dump_tokens(&settings, file);
+            true
+        }
+    };
+    if !ok {
+        std::process::exit(1);
     }
-    test_unidiff(&opts);
-    println!("\n");
 }
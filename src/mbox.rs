@@ -0,0 +1,383 @@
+/*
+ * The patches as mailed by "git format-patch" and "git send-email",
+ * one after another in an mbox: who wrote the change and when,
+ * the commit message, and the diff.
+ */
+
+/// A mail with a patch
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mail {
+    pub author_name: String,
+    pub author_email: String,
+    /// as in the Date header
+    pub date: String,
+    /// without the "[PATCH n/m]" in front of it
+    pub subject: String,
+    /// the rest of the commit message
+    pub body: String,
+    pub diff: String,
+}
+
+/*
+ * The mails start with a "From " line at the start of the file or after
+ * a blank line; the ones in the bodies are quoted as ">From ".
+ */
+pub fn split_mbox(text: &str) -> Vec<String> {
+    let mut mails = vec![];
+    let mut mail: Option<String> = None;
+    let mut after_blank = true;
+    for line in text.split_inclusive('\n') {
+        if after_blank && line.starts_with("From ") {
+            mails.extend(mail.take());
+            mail = Some(String::new());
+        } else {
+            mail.get_or_insert_with(String::new).push_str(line);
+        }
+        after_blank = line.trim_end().is_empty();
+    }
+    mails.extend(mail.filter(|mail| !mail.trim().is_empty()));
+    mails
+}
+
+fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+fn decode_q(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'_' => out.push(b' '),
+            b'=' => {
+                match (
+                    bytes.get(i + 1).and_then(|&b| hex_digit(b)),
+                    bytes.get(i + 2).and_then(|&b| hex_digit(b)),
+                ) {
+                    (Some(hi), Some(lo)) => {
+                        out.push(hi << 4 | lo);
+                        i += 2;
+                    }
+                    _ => out.push(b'='),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    out
+}
+
+fn decode_base64(text: &str) -> Vec<u8> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = vec![];
+    let (mut acc, mut bits) = (0u32, 0);
+    for b in text.bytes() {
+        let value = match ALPHABET.iter().position(|&c| c == b) {
+            Some(value) => value as u32,
+            None => continue,
+        };
+        acc = acc << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    out
+}
+
+/// The header with the "=?charset?Q?...?=" and "=?charset?B?...?=" words decoded, taken to be UTF-8
+fn decode_header(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let word = rest[start + 2..].splitn(3, '?').collect::<Vec<_>>();
+        let end = match word.as_slice() {
+            [_, _, tail] => tail.find("?=").map(|end| (tail, end)),
+            _ => None,
+        };
+        let (tail, end) = match end {
+            Some(end) => end,
+            None => break,
+        };
+        /* the whitespace between two encoded words is dropped */
+        let before = &rest[..start];
+        if !(after_word && before.trim().is_empty()) {
+            out.push_str(before);
+        }
+        let bytes = match word[1] {
+            "Q" | "q" => decode_q(&tail[..end]),
+            _ => decode_base64(&tail[..end]),
+        };
+        out.push_str(&String::from_utf8_lossy(&bytes));
+        rest = &tail[end + 2..];
+        after_word = true;
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The headers, unfolded, with the names in lowercase; and the body after them
+fn split_headers(text: &str) -> (Vec<(String, String)>, &str) {
+    let mut headers: Vec<(String, String)> = vec![];
+    let mut pos = 0;
+    for line in text.split_inclusive('\n') {
+        pos += line.len();
+        let line = line.trim_end();
+        if line.is_empty() {
+            return (headers, &text[pos..]);
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim_start());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.to_lowercase(), value.trim().to_string()));
+        }
+    }
+    (headers, "")
+}
+
+/// The name and the address of "Name <address>", "address (Name)" or just the address
+fn parse_address(from: &str) -> (String, String) {
+    if let (Some(start), Some(end)) = (from.find('<'), from.rfind('>')) {
+        let name = from[..start].trim().trim_matches('"').to_string();
+        return (name, from[start + 1..end].trim().to_string());
+    }
+    if let (Some(start), Some(end)) = (from.find('('), from.rfind(')')) {
+        let name = from[start + 1..end].trim().to_string();
+        return (name, from[..start].trim().to_string());
+    }
+    (String::new(), from.trim().to_string())
+}
+
+/// Strip the "Re:" and the "[PATCH ...]" off the start of the subject, as "git am" does
+fn clean_subject(subject: &str) -> String {
+    let mut subject = subject.trim();
+    loop {
+        if subject
+            .get(..3)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("re:"))
+        {
+            subject = subject[3..].trim_start();
+        } else if let Some(end) = subject.strip_prefix('[').and_then(|s| s.find(']')) {
+            subject = subject[end + 2..].trim_start();
+        } else {
+            return subject.to_string();
+        }
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// Days since 1970-01-01 of the date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+impl Mail {
+    pub fn parse(text: &str) -> Mail {
+        let (headers, body) = split_headers(text);
+        let mut mail = Mail::default();
+        mail.set_headers(&headers);
+        /* "From:", "Date:" and "Subject:" at the start of the body override the mail's */
+        let (in_body, rest) = split_headers(body);
+        let overrides = ["from", "date", "subject"];
+        let body =
+            if !in_body.is_empty() && in_body.iter().all(|(n, _)| overrides.contains(&&n[..])) {
+                mail.set_headers(&in_body);
+                rest
+            } else {
+                body
+            };
+        /* the message ends at the "---" line, the diff starts at its first header */
+        let mut message = String::new();
+        let mut diff_start = None;
+        let mut pos = 0;
+        let mut in_message = true;
+        for line in body.split_inclusive('\n') {
+            let is_diff = ["diff ", "--- ", "Index: "]
+                .iter()
+                .any(|prefix| line.starts_with(prefix));
+            if is_diff && diff_start.is_none() {
+                diff_start = Some(pos);
+                in_message = false;
+            } else if line.trim_end() == "---" {
+                in_message = false;
+            }
+            if in_message {
+                message.push_str(line);
+            }
+            pos += line.len();
+        }
+        mail.body = message.trim().to_string();
+        if let Some(start) = diff_start {
+            let diff = &body[start..];
+            /* the signature "git format-patch" puts after the diff */
+            let end = diff.find("\n-- \n").map_or(diff.len(), |end| end + 1);
+            mail.diff = diff[..end].to_string();
+        }
+        mail
+    }
+
+    fn set_headers(&mut self, headers: &[(String, String)]) {
+        for (name, value) in headers {
+            match name.as_str() {
+                "from" => {
+                    let (name, email) = parse_address(&decode_header(value));
+                    self.author_name = name;
+                    self.author_email = email;
+                }
+                "date" => self.date = value.clone(),
+                "subject" => self.subject = clean_subject(&decode_header(value)),
+                _ => {}
+            }
+        }
+    }
+
+    /// The subject, then the body after a blank line
+    pub fn message(&self) -> String {
+        if self.body.is_empty() {
+            format!("{}\n", self.subject)
+        } else {
+            format!("{}\n\n{}\n", self.subject, self.body)
+        }
+    }
+
+    /// The seconds since the epoch and the offset of the time zone in minutes, from the date
+    pub fn time(&self) -> Option<(i64, i32)> {
+        /* "Mon, 17 Sep 2018 12:34:56 +0200", the day of the week being optional */
+        let date = self.date.split_once(',').map_or(&self.date[..], |(_, d)| d);
+        let parts: Vec<&str> = date.split_whitespace().collect();
+        let (day, month, year, time, zone) = match parts.as_slice() {
+            [day, month, year, time, zone, ..] => (day, month, year, time, zone),
+            _ => return None,
+        };
+        let day: i64 = day.parse().ok()?;
+        let month = MONTHS
+            .iter()
+            .position(|m| month.to_lowercase().starts_with(m))? as i64
+            + 1;
+        let year: i64 = year.parse().ok()?;
+        let hms: Vec<i64> = time
+            .split(':')
+            .map(|n| n.parse().ok())
+            .collect::<Option<_>>()?;
+        let seconds = match hms.as_slice() {
+            [h, m] => h * 3600 + m * 60,
+            [h, m, s] => h * 3600 + m * 60 + s,
+            _ => return None,
+        };
+        let offset: i32 = zone.parse().ok()?;
+        let offset = offset.signum() * (offset.abs() / 100 * 60 + offset.abs() % 100);
+        let local = days_from_civil(year, month, day) * 86400 + seconds;
+        Some((local - offset as i64 * 60, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{FileSystem, MemoryFileSystem};
+    use crate::patch::Patcher;
+    use crate::patchset::PatchSet;
+
+    const MBOX: &str = "From 1234567890abcdef Mon Sep 17 00:00:00 2001\n\
+        From: =?UTF-8?q?Andr=C3=A9=20Dupont?= <andre@example.com>\n\
+        Date: Tue, 2 Jan 2024 10:30:00 +0100\n\
+        Subject: [PATCH 1/2] Fix the\n frobnicator\n\
+        \n\
+        It was broken.\n\
+        ---\n \
+        f.c | 2 +-\n\
+        \n\
+        diff --git a/f.c b/f.c\n\
+        --- a/f.c\n\
+        +++ b/f.c\n\
+        @@ -1,1 +1,1 @@\n\
+        -a;\n\
+        +b;\n\
+        -- \n\
+        2.43.0\n\
+        \n\
+        From 1234567890abcdef Mon Sep 17 00:00:00 2001\n\
+        From: Bob <bob@example.com>\n\
+        Subject: [PATCH 2/2] Second\n\
+        \n\
+        diff --git a/g.c b/g.c\n";
+
+    #[test]
+    fn mbox_split_and_parse() {
+        let mails = split_mbox(MBOX);
+        assert_eq!(mails.len(), 2);
+        let mail = Mail::parse(&mails[0]);
+        assert_eq!(mail.author_name, "André Dupont");
+        assert_eq!(mail.author_email, "andre@example.com");
+        assert_eq!(mail.subject, "Fix the frobnicator");
+        assert_eq!(mail.message(), "Fix the frobnicator\n\nIt was broken.\n");
+        assert_eq!(
+            mail.diff,
+            "diff --git a/f.c b/f.c\n--- a/f.c\n+++ b/f.c\n@@ -1,1 +1,1 @@\n-a;\n+b;\n"
+        );
+        assert_eq!(mail.time(), Some((1704187800, 60)));
+        let mail = Mail::parse(&mails[1]);
+        assert_eq!(mail.subject, "Second");
+        assert_eq!(mail.message(), "Second\n");
+    }
+
+    #[test]
+    fn mbox_one_line_files() {
+        /* "git format-patch" leaves the count out of the ranges of one line */
+        let mbox = "From 1234567890abcdef Mon Sep 17 00:00:00 2001\n\
+            From: Bob <bob@example.com>\n\
+            Subject: [PATCH] One-liners\n\
+            \n\
+            ---\n\
+            diff --git a/f.c b/f.c\n\
+            index 1111111..2222222 100644\n\
+            --- a/f.c\n\
+            +++ b/f.c\n\
+            @@ -1 +1 @@\n\
+            -a;\n\
+            +b;\n\
+            diff --git a/g.c b/g.c\n\
+            new file mode 100644\n\
+            index 0000000..3333333\n\
+            --- /dev/null\n\
+            +++ b/g.c\n\
+            @@ -0,0 +1 @@\n\
+            +c;\n\
+            -- \n\
+            2.43.0\n";
+        let mail = Mail::parse(&split_mbox(mbox)[0]);
+        let patch = PatchSet::parse(&mail.diff).unwrap();
+        assert_eq!(patch.files().len(), 2);
+        let fs = MemoryFileSystem::new();
+        fs.insert("f.c", "a;\n");
+        let patcher = Patcher::default();
+        for file in patch.files() {
+            patcher.apply_file_patch(file, &fs).unwrap();
+        }
+        assert_eq!(fs.read_to_string("f.c").unwrap(), "b;\n");
+        assert_eq!(fs.read_to_string("g.c").unwrap(), "c;\n");
+    }
+
+    #[test]
+    fn mbox_subject_prefixes() {
+        assert_eq!(clean_subject("Re: [PATCH v2] mañana"), "mañana");
+        assert_eq!(clean_subject("[PATCH] ñu"), "ñu");
+        assert_eq!(clean_subject("RE:re: Fix"), "Fix");
+    }
+}
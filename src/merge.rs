@@ -0,0 +1,307 @@
+/*
+ * The three-way merge of the texts token by token: the changes of the
+ * two sides are combined wherever they touch different tokens, even on
+ * the same line, and the whitespace is merged separately from the tokens,
+ * so that one side reformatting the code does not conflict with the other
+ * changing it.
+ */
+use crate::indent::{self, IndentStyle};
+use crate::parse::{parse_string_with, EditedParseStruct, ParseStruct, TextAtom, Tokenizer};
+use crate::patch::MergeStyle;
+use diffus::edit::{self, collection};
+use diffus::Diffable;
+
+#[derive(Clone, Debug)]
+pub struct MergeOptions {
+    pub tokenizer: Tokenizer,
+    /// whether the base is shown in the conflicts too
+    pub style: MergeStyle,
+    /// the length of the conflict markers
+    pub marker_size: usize,
+    /// put after the markers of ours, the base and theirs
+    pub labels: [Option<String>; 3],
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions {
+            tokenizer: Tokenizer::Code,
+            style: MergeStyle::Merge,
+            marker_size: 7,
+            labels: Default::default(),
+        }
+    }
+}
+
+/// The merged text, with the conflicts between the markers
+#[derive(Clone, Debug)]
+pub struct Merged {
+    pub text: String,
+    pub conflicts: usize,
+}
+
+enum Segment {
+    Clean(String),
+    Conflict([String; 3]),
+}
+
+/// For each atom of the base, the index of the same token on the other side
+fn matching(base: &ParseStruct, other: &ParseStruct) -> Vec<Option<usize>> {
+    let diff = match base.diff(other) {
+        edit::Edit::Change(EditedParseStruct {
+            atoms: edit::Edit::Change(diff),
+        }) => diff,
+        _ => return (0..base.atoms.len()).map(Some).collect(),
+    };
+    let mut matches = vec![];
+    let mut oi = 0;
+    for edit in diff {
+        match edit {
+            collection::Edit::Copy(_) | collection::Edit::Change(_) => {
+                matches.push(Some(oi));
+                oi += 1;
+            }
+            collection::Edit::Remove(_) => matches.push(None),
+            collection::Edit::Insert(_) => oi += 1,
+        }
+    }
+    matches
+}
+
+fn tokens(atoms: &[TextAtom]) -> Vec<&str> {
+    atoms.iter().map(|atom| atom.token_value.as_str()).collect()
+}
+
+fn text(atoms: &[TextAtom]) -> String {
+    atoms
+        .iter()
+        .map(|atom| format!("{}{}", atom.leading_ws, atom.token_value))
+        .collect()
+}
+
+/// The atoms of one side, indented in the style the code around them has in the result
+fn restyled(atoms: &[TextAtom], from: &IndentStyle, to: &IndentStyle) -> String {
+    atoms
+        .iter()
+        .map(|atom| {
+            format!(
+                "{}{}",
+                indent::convert_indent(&atom.leading_ws, from, to),
+                atom.token_value
+            )
+        })
+        .collect()
+}
+
+fn marker(ch: char, opts: &MergeOptions, label: &Option<String>) -> String {
+    let marker = ch.to_string().repeat(opts.marker_size);
+    match label {
+        Some(label) => format!("{} {}\n", marker, label),
+        None => format!("{}\n", marker),
+    }
+}
+
+/*
+ * Write out the sides of a conflict, which span whole lines; the lines
+ * all of them start or end with are left outside of the markers.
+ * Returns whether it is a conflict after all.
+ */
+fn write_conflict(out: &mut String, sides: &[String; 3], opts: &MergeOptions) -> bool {
+    let lines: Vec<Vec<&str>> = sides
+        .iter()
+        .map(|side| side.split_inclusive('\n').collect())
+        .collect();
+    let shortest = lines.iter().map(Vec::len).min().unwrap();
+    let head = (0..shortest)
+        .take_while(|&i| lines[1][i] == lines[0][i] && lines[2][i] == lines[0][i])
+        .count();
+    let tail = (0..shortest - head)
+        .take_while(|&i| {
+            let line = |side: usize| lines[side][lines[side].len() - 1 - i];
+            line(1) == line(0) && line(2) == line(0)
+        })
+        .count();
+    let middle = |side: &Vec<&str>| side[head..side.len() - tail].concat();
+    out.push_str(&lines[0][..head].concat());
+    let (ours, base, theirs) = (middle(&lines[0]), middle(&lines[1]), middle(&lines[2]));
+    let conflict = ours != theirs;
+    if conflict {
+        let push_side = |out: &mut String, side: &str| {
+            out.push_str(side);
+            if !side.is_empty() && !side.ends_with('\n') {
+                out.push('\n');
+            }
+        };
+        out.push_str(&marker('<', opts, &opts.labels[0]));
+        push_side(out, &ours);
+        if opts.style == MergeStyle::Diff3 {
+            out.push_str(&marker('|', opts, &opts.labels[1]));
+            push_side(out, &base);
+        }
+        out.push_str(&marker('=', opts, &None));
+        push_side(out, &theirs);
+        out.push_str(&marker('>', opts, &opts.labels[2]));
+    } else {
+        out.push_str(&ours);
+    }
+    out.push_str(&lines[0][lines[0].len() - tail..].concat());
+    conflict
+}
+
+/// Merge the changes from the base to ours and to theirs
+pub fn merge3(base: &str, ours: &str, theirs: &str, opts: &MergeOptions) -> Merged {
+    let base = parse_string_with(base, opts.tokenizer);
+    let ours = parse_string_with(ours, opts.tokenizer);
+    let theirs = parse_string_with(theirs, opts.tokenizer);
+    let (ours_matching, theirs_matching) = (matching(&base, &ours), matching(&base, &theirs));
+    let (ours_style, theirs_style) = (IndentStyle::infer(&ours), IndentStyle::infer(&theirs));
+    /* the style of the side which has reformatted the code, if either has */
+    let style = if ours_style != IndentStyle::infer(&base) {
+        ours_style
+    } else {
+        theirs_style
+    };
+
+    let mut segments = vec![];
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        /* the next token both sides kept */
+        let next =
+            (b..base.atoms.len()).find_map(|i| match (ours_matching[i], theirs_matching[i]) {
+                (Some(oi), Some(ti)) => Some((i, oi, ti)),
+                _ => None,
+            });
+        let (nb, no, nt) = next.unwrap_or((base.atoms.len(), ours.atoms.len(), theirs.atoms.len()));
+        let (base_chunk, ours_chunk, theirs_chunk) =
+            (&base.atoms[b..nb], &ours.atoms[o..no], &theirs.atoms[t..nt]);
+        if tokens(ours_chunk) == tokens(base_chunk) {
+            segments.push(Segment::Clean(restyled(
+                theirs_chunk,
+                &theirs_style,
+                &style,
+            )));
+        } else if tokens(theirs_chunk) == tokens(base_chunk)
+            || tokens(ours_chunk) == tokens(theirs_chunk)
+        {
+            segments.push(Segment::Clean(restyled(ours_chunk, &ours_style, &style)));
+        } else {
+            segments.push(Segment::Conflict([
+                text(ours_chunk),
+                text(base_chunk),
+                text(theirs_chunk),
+            ]));
+        }
+        let (nb, no, nt) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        /* the whitespace changed on one side wins */
+        let (base_atom, ours_atom, theirs_atom) =
+            (&base.atoms[nb], &ours.atoms[no], &theirs.atoms[nt]);
+        let atom = if ours_atom.leading_ws == base_atom.leading_ws {
+            restyled(std::slice::from_ref(theirs_atom), &theirs_style, &style)
+        } else {
+            restyled(std::slice::from_ref(ours_atom), &ours_style, &style)
+        };
+        segments.push(Segment::Clean(atom));
+        b = nb + 1;
+        o = no + 1;
+        t = nt + 1;
+    }
+
+    let mut out = String::new();
+    let mut conflicts = 0;
+    let mut segments = segments.into_iter().peekable();
+    while let Some(segment) = segments.next() {
+        let mut sides = match segment {
+            Segment::Clean(text) => {
+                out.push_str(&text);
+                continue;
+            }
+            Segment::Conflict(sides) => sides,
+        };
+        /* the conflict takes in the whole lines it is on, and the other conflicts there */
+        let line_start = out.rfind('\n').map_or(0, |pos| pos + 1);
+        let prefix = out.split_off(line_start);
+        for side in sides.iter_mut() {
+            side.insert_str(0, &prefix);
+        }
+        while let Some(segment) = segments.peek_mut() {
+            match segment {
+                Segment::Clean(text) => match text.find('\n') {
+                    Some(pos) => {
+                        let rest = text.split_off(pos + 1);
+                        for side in sides.iter_mut() {
+                            side.push_str(text);
+                        }
+                        *text = rest;
+                        break;
+                    }
+                    None => {
+                        for side in sides.iter_mut() {
+                            side.push_str(text);
+                        }
+                    }
+                },
+                Segment::Conflict(more) => {
+                    for (side, more) in sides.iter_mut().zip(more.iter()) {
+                        side.push_str(more);
+                    }
+                }
+            }
+            segments.next();
+        }
+        if write_conflict(&mut out, &sides, opts) {
+            conflicts += 1;
+        }
+    }
+    Merged {
+        text: out,
+        conflicts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> Merged {
+        merge3(base, ours, theirs, &MergeOptions::default())
+    }
+
+    #[test]
+    fn merge_same_line() {
+        let merged = merge("f(a, b);\n", "f(x, b);\n", "f(a, y);\n");
+        assert_eq!(merged.text, "f(x, y);\n");
+        assert_eq!(merged.conflicts, 0);
+    }
+
+    #[test]
+    fn merge_reformatted() {
+        let base = "int f() {\n\tif (a)\n\t\treturn 1;\n\treturn 0;\n}\n";
+        let ours = "int f() {\n    if (a)\n        return 1;\n    return 0;\n}\n";
+        let theirs = "int f() {\n\tif (a)\n\t\treturn 2;\n\tlog();\n\treturn 0;\n}\n";
+        let merged = merge(base, ours, theirs);
+        assert_eq!(
+            merged.text,
+            "int f() {\n    if (a)\n        return 2;\n    log();\n    return 0;\n}\n"
+        );
+        assert_eq!(merged.conflicts, 0);
+    }
+
+    #[test]
+    fn merge_conflict() {
+        let base = "a;\nb = 1;\nc;\n";
+        let opts = MergeOptions {
+            style: MergeStyle::Diff3,
+            labels: [Some("ours".to_string()), None, Some("theirs".to_string())],
+            ..Default::default()
+        };
+        let merged = merge3(base, "a;\nb = 2;\nc;\n", "a;\nb = 3;\nc;\n", &opts);
+        assert_eq!(
+            merged.text,
+            "a;\n<<<<<<< ours\nb = 2;\n|||||||\nb = 1;\n=======\nb = 3;\n>>>>>>> theirs\nc;\n"
+        );
+        assert_eq!(merged.conflicts, 1);
+    }
+}
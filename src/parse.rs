@@ -0,0 +1,147 @@
+use diffus::Same;
+use diffus_derive::Diffus;
+
+/// A token together with the whitespace preceding it
+#[derive(Diffus, Debug, Clone)]
+pub struct TextAtom {
+    pub token_value: String,
+    /// optional tag: the atoms with the same ID are the same token
+    pub token_uuid: String,
+    pub leading_ws: String,
+}
+
+impl Same for TextAtom {
+    fn same(&self, other: &Self) -> bool {
+        if self.token_uuid.is_empty() {
+            if other.token_uuid.is_empty() {
+                /* we assume that with empty IDs the tokens can match */
+                self.token_value == other.token_value
+            } else {
+                false // one tagged, the other not = no match
+            }
+        } else if other.token_uuid.is_empty() {
+            false // one tagged, the other not = no match
+        } else {
+            /* the same ID is the same token, even if its value was edited */
+            self.token_uuid == other.token_uuid
+        }
+    }
+}
+
+impl PartialEq for TextAtom {
+    fn eq(&self, other: &Self) -> bool {
+        self.token_value == other.token_value
+    }
+}
+
+/// A text split into atoms, concatenating them gives back the text
+#[derive(Diffus, Debug, Clone)]
+pub struct ParseStruct {
+    pub atoms: Vec<TextAtom>,
+}
+
+/// How the text is split into tokens
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Tokenizer {
+    /// the identifiers and the runs of punctuation are separate tokens
+    #[default]
+    Code,
+    /// anything between the whitespace is a token, for the prose
+    Words,
+}
+
+impl std::str::FromStr for Tokenizer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "code" => Ok(Tokenizer::Code),
+            "words" => Ok(Tokenizer::Words),
+            _ => Err(format!("unknown tokenizer '{}', expected code or words", s)),
+        }
+    }
+}
+
+enum ParseTokenState {
+    LeadingWhiteSpace,
+    TokenValue,
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn parse_token(input: &str, i: usize, tokenizer: Tokenizer) -> (Option<TextAtom>, usize) {
+    let mut atom = TextAtom {
+        token_value: String::new(),
+        token_uuid: String::new(),
+        leading_ws: String::new(),
+    };
+
+    if i >= input.len() {
+        return (None, 0);
+    }
+
+    let mut state = ParseTokenState::LeadingWhiteSpace;
+    let mut is_id = false;
+
+    let char_indices = input[i..].char_indices();
+    for (ci, ch) in char_indices {
+        match state {
+            ParseTokenState::LeadingWhiteSpace => {
+                if ch.is_whitespace() || (ch == '\\') {
+                    /*
+                     * testing only for "\" in this case is a gross simplification,
+                     * since we really should test for "\" followed by "\n", however
+                     * if we are in the "leading whitespace" state, there should be
+                     * no other valid scenario where "\" may appear, so this
+                     * shortcut should work.
+                     */
+                    atom.leading_ws.push(ch)
+                } else {
+                    atom.token_value.push(ch);
+                    is_id = is_ident(ch);
+                    state = ParseTokenState::TokenValue;
+                }
+            }
+            ParseTokenState::TokenValue => {
+                let new_token = tokenizer == Tokenizer::Code && is_id != is_ident(ch);
+                if ch.is_whitespace() || new_token {
+                    return (Some(atom), ci);
+                } else {
+                    atom.token_value.push(ch);
+                }
+            }
+        }
+    }
+
+    (Some(atom), input[i..].len())
+}
+
+pub fn parse_string(input: &str) -> ParseStruct {
+    parse_string_with(input, Tokenizer::Code)
+}
+
+pub fn parse_string_with(input: &str, tokenizer: Tokenizer) -> ParseStruct {
+    let mut atoms: Vec<TextAtom> = vec![];
+    let mut i = 0;
+
+    while let (Some(token), delta_i) = parse_token(input, i, tokenizer) {
+        // println!("Token: {:#?}, delta_i: {}", &token, delta_i);
+        atoms.push(token);
+        i += delta_i;
+    }
+    ParseStruct { atoms }
+}
+
+pub fn parse_struct2str(p: &ParseStruct) -> String {
+    let mut out_acc = String::new();
+    for atom in &p.atoms {
+        out_acc.push_str(&atom2str(atom));
+    }
+    out_acc
+}
+
+pub fn atom2str(atom: &TextAtom) -> String {
+    format!("{}{}", atom.leading_ws, atom.token_value)
+}
@@ -0,0 +1,1972 @@
+/*
+ * Applying the hunks to the tokenized text.
+ */
+use crate::fs::FileSystem;
+use crate::indent;
+use crate::merge::{merge3, MergeOptions};
+use crate::parse::{
+    parse_string, parse_string_with, parse_struct2str, EditedParseStruct, EditedTextAtom,
+    ParseStruct, TextAtom, Tokenizer,
+};
+use crate::patchset::{FilePatch, Hunk, PatchSet};
+use crate::print::{format_diff, format_diff_c};
+use crate::worddiff::{mark_changes, WordDiffMode, WordDiffOptions};
+use diffus::edit::{self, collection, string};
+use diffus::Diffable;
+use log::{debug, log_enabled, trace, Level};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum PatchError {
+    /// the diff could not be parsed
+    Parse(unidiff::Error),
+    /// the context of the hunk (numbered from 1) was not found
+    ContextNotFound {
+        hunk: usize,
+        line: usize,
+    },
+    Io {
+        path: String,
+        error: std::io::Error,
+    },
+    /// the file to be created is already there
+    FileExists {
+        path: String,
+    },
+    /// the file name is absolute or goes up with "..", out of the tree being patched
+    UnsafePath {
+        path: String,
+    },
+    /// some of the hunks could not be applied, so the file was left alone
+    HunksFailed {
+        path: String,
+        applied: Applied,
+    },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Parse(e) => write!(f, "error parsing diff: {}", e),
+            PatchError::ContextNotFound { hunk, line } => {
+                write!(f, "can not find context for hunk #{} at {}", hunk, line)
+            }
+            PatchError::Io { path, error } => write!(f, "{}: {}", path, error),
+            PatchError::FileExists { path } => write!(f, "{}: file to create already exists", path),
+            PatchError::UnsafePath { path } => {
+                write!(f, "{}: refusing to patch a file outside the tree", path)
+            }
+            PatchError::HunksFailed { path, applied } => {
+                let failures: Vec<String> = applied
+                    .hunks
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, hunk)| match hunk.status {
+                        HunkStatus::Failed => {
+                            Some(format!("hunk #{} FAILED at {}", i + 1, hunk.line))
+                        }
+                        HunkStatus::AlreadyApplied => {
+                            Some(format!("hunk #{} already applied at {}", i + 1, hunk.line))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                write!(f, "{}: {}", path, failures.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// What has become of a hunk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HunkStatus {
+    /// the context matched, whitespace and all
+    Applied,
+    /// the target side is already in the text, so there was nothing to do
+    AlreadyApplied,
+    /// the context matched once the whitespace was ignored
+    Fuzzed,
+    /// the context was not found
+    Failed,
+    /// the context was not found, so the hunk was written between conflict markers
+    Conflict,
+    /// the context was not found, so the hunk was applied to the file the diff was made against,
+    /// and that merged into the text
+    Merged,
+    /// not attempted, as the file could not be patched at all
+    Rejected,
+    /// left out when asked what to do with it
+    Skipped,
+}
+
+impl HunkStatus {
+    pub fn is_applied(self) -> bool {
+        matches!(
+            self,
+            HunkStatus::Applied | HunkStatus::Fuzzed | HunkStatus::Merged
+        )
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HunkStatus::Applied => "applied",
+            HunkStatus::AlreadyApplied => "already-applied",
+            HunkStatus::Fuzzed => "fuzzed",
+            HunkStatus::Failed => "failed",
+            HunkStatus::Conflict => "conflict",
+            HunkStatus::Merged => "merged",
+            HunkStatus::Rejected => "rejected",
+            HunkStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// Where a hunk has been applied, or for the hunks which were not, where it was expected
+#[derive(Clone, Debug)]
+pub struct AppliedHunk {
+    pub status: HunkStatus,
+    /// the index of the atom in the text before the hunk where the match starts
+    pub token_pos: usize,
+    /// the index of the atom just past the match
+    pub token_end: usize,
+    /// the line of the first matched atom, counting from 1
+    pub line: usize,
+    /// the line of the last matched atom
+    pub end_line: usize,
+    /// how many lines away that is from where the hunk said it would be
+    pub offset: isize,
+    /// the share of the matched atoms whose whitespace is as in the hunk
+    pub similarity: f64,
+    /// how many atoms of the result have whitespace different from the hunk's
+    pub ws_adjusted: usize,
+}
+
+impl AppliedHunk {
+    pub(crate) fn unapplied(status: HunkStatus, line: usize) -> Self {
+        AppliedHunk {
+            status,
+            token_pos: 0,
+            token_end: 0,
+            line,
+            end_line: line,
+            offset: 0,
+            similarity: 0.0,
+            ws_adjusted: 0,
+        }
+    }
+}
+
+/// The result of applying the hunks to a text
+#[derive(Clone, Debug)]
+pub struct Applied {
+    pub text: String,
+    pub hunks: Vec<AppliedHunk>,
+}
+
+/// A place in the text where the context of a hunk is, when choosing where to apply it
+#[derive(Clone, Debug)]
+pub struct HunkCandidate {
+    pub applied: AppliedHunk,
+    /// the matched part of the text, before and after the hunk is applied
+    old: ParseStruct,
+    new: ParseStruct,
+}
+
+impl HunkCandidate {
+    /// What the hunk changes there, marked token by token as in the word diff
+    pub fn preview(&self, color: bool) -> String {
+        let opts = WordDiffOptions {
+            mode: if color {
+                WordDiffMode::Color
+            } else {
+                WordDiffMode::Plain
+            },
+            color,
+            ..Default::default()
+        };
+        mark_changes(&self.old, &self.new, &opts)
+    }
+}
+
+/// What to do with a hunk, when asked
+#[derive(Clone, Debug)]
+pub enum HunkChoice {
+    /// apply it at the candidate with this index, skip it if there is no such candidate
+    Apply(usize),
+    Skip,
+    /// ask again with this hunk in its place
+    Edit(Hunk),
+}
+
+/// What has been done to the file as a whole
+#[derive(Clone, Debug, PartialEq)]
+pub enum FileOperation {
+    Modified,
+    Created,
+    /// the file was removed, unless something other than whitespace was left
+    Removed,
+    Renamed {
+        from: String,
+    },
+}
+
+/// The result of applying the hunks for a file, with the path it was written to
+#[derive(Clone, Debug)]
+pub struct AppliedFile {
+    pub path: String,
+    pub operation: FileOperation,
+    pub applied: Applied,
+}
+
+/// Applies the hunks to the texts, tolerating the differences in formatting
+#[derive(Clone, Debug, Default)]
+pub struct Patcher {
+    options: PatchOptions,
+}
+
+/// Which whitespace ends up on the atoms of the patched region
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WsPolicy {
+    /// the context keeps the whitespace of the target, inserted atoms as in the patch
+    KeepTarget,
+    /// everything is as in the patch
+    TakePatch,
+    /// the context keeps the whitespace of the target, inserted atoms are re-indented
+    Infer,
+}
+
+impl std::str::FromStr for WsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-target" => Ok(WsPolicy::KeepTarget),
+            "take-patch" => Ok(WsPolicy::TakePatch),
+            "infer" => Ok(WsPolicy::Infer),
+            x => Err(format!(
+                "unknown whitespace policy '{}', expected keep-target, take-patch or infer",
+                x
+            )),
+        }
+    }
+}
+
+/// How the hunks which do not apply are written into the text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergeStyle {
+    /// the lines of the text, then the target side of the hunk
+    Merge,
+    /// the source side of the hunk in between
+    Diff3,
+}
+
+impl std::str::FromStr for MergeStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge" => Ok(MergeStyle::Merge),
+            "diff3" => Ok(MergeStyle::Diff3),
+            x => Err(format!(
+                "unknown merge style '{}', expected merge or diff3",
+                x
+            )),
+        }
+    }
+}
+
+/// The knobs of applying the patches
+#[derive(Clone, Copy, Debug)]
+pub struct PatchOptions {
+    /// whose whitespace wins
+    pub ws: WsPolicy,
+    /// carry over the changes of the hunks which only change the whitespace
+    pub ws_only_edits: bool,
+    /// how many leading components to strip off the file names in the diff
+    pub strip: usize,
+    /// how the texts are split into tokens
+    pub tokenizer: Tokenizer,
+    /// the hunks whose context has less of its whitespace as in the text fail
+    pub min_similarity: f64,
+    /// keep the original of each file changed as the file name with ".orig" appended
+    pub backup: bool,
+    /// write the hunks which do not apply between conflict markers, instead of failing them
+    pub merge: Option<MergeStyle>,
+}
+
+impl Default for PatchOptions {
+    fn default() -> Self {
+        PatchOptions {
+            ws: WsPolicy::Infer,
+            ws_only_edits: false,
+            strip: 1,
+            tokenizer: Tokenizer::Code,
+            min_similarity: 0.0,
+            backup: false,
+            merge: None,
+        }
+    }
+}
+
+impl PatchOptions {
+    fn patch_ws(&self) -> PatchWs {
+        let take_patch = self.ws == WsPolicy::TakePatch;
+        PatchWs {
+            copied: take_patch,
+            changed: take_patch,
+        }
+    }
+}
+
+/// Which of the matched atoms take their whitespace from the patch
+#[derive(Clone, Copy, Debug)]
+struct PatchWs {
+    /// the atoms with the same whitespace on both sides of the hunk
+    copied: bool,
+    /// the atoms whose whitespace the hunk changes
+    changed: bool,
+}
+
+/// The value of the token before the edit, rebuilt from its character diff
+pub(crate) fn old_token_value(value_edits: &[string::Edit]) -> String {
+    value_edits
+        .iter()
+        .filter_map(|edit| match edit {
+            string::Edit::Copy(ch) | string::Edit::Remove(ch) => Some(*ch),
+            string::Edit::Insert(_) => None,
+        })
+        .collect()
+}
+
+/*
+ * Every line of the hunk is preceded by a newline, so that the first
+ * token of each line carries it in the leading whitespace, same
+ * as it happens when tokenizing the file being patched.
+ */
+fn join_lines(lines: &[unidiff::Line]) -> String {
+    lines.iter().map(|x| format!("\n{}", x.value)).collect()
+}
+
+/// One side (source or target) of a hunk, tokenized
+pub(crate) struct HunkSide {
+    pub(crate) parse: ParseStruct,
+    /* whitespace after the last token, which would belong to the next token */
+    trailing_ws: String,
+}
+
+pub(crate) fn parse_hunk_side(
+    lines: &[unidiff::Line],
+    at_file_start: bool,
+    no_newline: bool,
+    tokenizer: Tokenizer,
+) -> HunkSide {
+    let text = join_lines(lines);
+    /* at the start of the file there is no previous line to terminate */
+    let text = if at_file_start {
+        text.strip_prefix('\n').unwrap_or(&text)
+    } else {
+        &text
+    };
+    let body = text.trim_end_matches(char::is_whitespace);
+    let mut trailing_ws = text[body.len()..].to_string();
+    if !lines.is_empty() && !no_newline {
+        trailing_ws.push('\n');
+    }
+    HunkSide {
+        parse: parse_string_with(body, tokenizer),
+        trailing_ws,
+    }
+}
+
+pub fn find_needle<N, H>(needle: &[N], haystack: &[H], debug: bool) -> Option<usize>
+where
+    N: PartialEq + std::cmp::PartialEq<H> + std::fmt::Debug,
+    H: PartialEq<N> + std::fmt::Debug,
+{
+    for hi in 0..haystack.len() {
+        let mut match_count = 0;
+        for (ni, cn) in needle.iter().enumerate() {
+            if debug && hi + ni < haystack.len() {
+                trace!("pos {}  : {:?} / {:?}", ni, &cn, &haystack[hi + ni]);
+            }
+            if hi + ni < haystack.len() && cn == &haystack[hi + ni] {
+                match_count += 1;
+            } else {
+                break;
+            }
+            if debug {
+                trace!("    {:?}  vs  {:?}", &cn, &haystack[hi + ni]);
+            }
+        }
+        if debug {
+            trace!("   matches: {} / {}", match_count, needle.len());
+        }
+
+        if match_count == needle.len() {
+            return Some(hi);
+        }
+    }
+    None
+}
+
+/*
+ * Besides the output atoms, records for each atom of the "right" side
+ * the index of the atom of the source file it was matched with, if any.
+ * Whether the matched atoms keep the whitespace they have in the source
+ * file depends on the whitespace policy. Returns how many atoms of the
+ * source file the edits cover, None if they do not match the file there.
+ */
+fn apply_patch(
+    out_file: &mut ParseStruct,
+    origin: &mut Vec<Option<usize>>,
+    src_file: &ParseStruct,
+    p: usize,
+    right: &ParseStruct,
+    diff: diffus::edit::Edit<'_, ParseStruct>,
+    patch_ws: PatchWs,
+) -> Option<usize> {
+    let mut atom_index = 0;
+    let mut src_skip = 0;
+    /* the atom of the file where the edit is, if it has the token the edit expects */
+    let src_atom = |src_skip: usize, token_value: &str| {
+        src_file
+            .atoms
+            .get(p + src_skip)
+            .filter(|atom| atom.token_value == token_value)
+    };
+    let copied_atom = |src_atom: &TextAtom, patch_atom: &TextAtom| {
+        if patch_ws.copied {
+            patch_atom.clone()
+        } else {
+            src_atom.clone()
+        }
+    };
+
+    match diff {
+        edit::Edit::Copy(_) => {
+            for atom in &right.atoms {
+                let src_atom = src_atom(src_skip, &atom.token_value)?;
+                out_file.atoms.push(copied_atom(src_atom, atom));
+                origin.push(Some(p + src_skip));
+                src_skip += 1;
+            }
+        }
+        edit::Edit::Change(EditedParseStruct { atoms }) => {
+            let diff = atoms;
+            match diff {
+                edit::Edit::Copy(x) => {
+                    for xx in x {
+                        let src_atom = src_atom(src_skip, &xx.token_value)?;
+                        out_file.atoms.push(copied_atom(src_atom, xx));
+                        origin.push(Some(p + src_skip));
+                        src_skip += 1;
+                    }
+                }
+                edit::Edit::Change(diff) => {
+                    for edit in diff {
+                        match edit {
+                            collection::Edit::Copy(elem) => {
+                                let src_atom = src_atom(src_skip, &elem.token_value)?;
+                                out_file.atoms.push(copied_atom(src_atom, elem));
+                                origin.push(Some(p + src_skip));
+                                atom_index += 1;
+                                src_skip += 1;
+                            }
+                            collection::Edit::Insert(elem) => {
+                                out_file.atoms.push(elem.clone());
+                                origin.push(None);
+                                atom_index += 1;
+                            }
+                            collection::Edit::Remove(elem) => {
+                                /* do not push out_file.atoms.push(elem.clone()); */
+                                src_atom(src_skip, &elem.token_value)?;
+                                src_skip += 1;
+                            }
+                            collection::Edit::Change(EditedTextAtom { token_value, .. }) => {
+                                let right_atom = right.atoms.get(atom_index)?;
+                                /* the file must have the value the hunk changes */
+                                let old_value = match token_value {
+                                    edit::Edit::Copy(_) => right_atom.token_value.clone(),
+                                    edit::Edit::Change(value_edits) => {
+                                        old_token_value(&value_edits)
+                                    }
+                                };
+                                let src_atom = src_atom(src_skip, &old_value)?;
+                                let ws = if patch_ws.changed {
+                                    &right_atom.leading_ws
+                                } else {
+                                    &src_atom.leading_ws
+                                };
+                                out_file.atoms.push(TextAtom {
+                                    token_value: right_atom.token_value.clone(),
+                                    token_uuid: right_atom.token_uuid.clone(),
+                                    leading_ws: ws.to_string(),
+                                });
+                                origin.push(Some(p + src_skip));
+                                atom_index += 1;
+                                src_skip += 1;
+                            }
+                        };
+                    }
+                }
+            };
+        }
+    }
+    Some(src_skip)
+}
+
+/// Where the context of a hunk came closest to matching the text
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// the index of the atom where the longest partial match starts
+    pub token_pos: usize,
+    /// how many atoms of the context matched there, out of how many
+    pub matched: usize,
+    pub total: usize,
+    /// the line of the text where the match broke off, counting from 1
+    pub line: usize,
+    /// the line of the old file the unmatched token is on, per the hunk
+    pub hunk_line: usize,
+    pub expected: String,
+    /// None if the text ended first
+    pub found: Option<String>,
+}
+
+/// Where the longest prefix of the needle is in the text, and how long it is
+fn best_partial_match(needle: &[TextAtom], text: &ParseStruct) -> (usize, usize) {
+    let prefix_len = |pos: usize| {
+        needle
+            .iter()
+            .zip(&text.atoms[pos..])
+            .take_while(|(n, h)| n == h)
+            .count()
+    };
+    (0..text.atoms.len().max(1))
+        .map(|pos| (pos, prefix_len(pos.min(text.atoms.len()))))
+        .fold((0, 0), |best, cur| if cur.1 > best.1 { cur } else { best })
+}
+
+/// Why the context of the hunk is not in the text, None if it is
+pub fn find_mismatch(original: &str, hunk: &Hunk, tokenizer: Tokenizer) -> Option<Mismatch> {
+    let text = parse_string_with(original, tokenizer);
+    let needle = parse_hunk_side(
+        &hunk.source_lines(),
+        false,
+        hunk.no_newline.source,
+        tokenizer,
+    )
+    .parse;
+    if needle.atoms.is_empty() || find_needle(&needle.atoms, &text.atoms, false).is_some() {
+        return None;
+    }
+    let (token_pos, matched) = best_partial_match(&needle.atoms, &text);
+    /* the first line of the hunk gets a newline in front of it */
+    let hunk_line = hunk.source_start + line_of_atom(&needle, matched) - 2;
+    Some(Mismatch {
+        token_pos,
+        matched,
+        total: needle.atoms.len(),
+        line: line_of_atom(&text, token_pos + matched),
+        hunk_line,
+        expected: needle.atoms[matched].token_value.clone(),
+        found: text
+            .atoms
+            .get(token_pos + matched)
+            .map(|atom| atom.token_value.clone()),
+    })
+}
+
+/// The line number, counting from 1, on which the atom starts
+pub(crate) fn line_of_atom(parse: &ParseStruct, pos: usize) -> usize {
+    let newlines: usize = parse.atoms[..pos]
+        .iter()
+        .map(|atom| atom.leading_ws.matches('\n').count())
+        .sum();
+    let own_newlines = parse
+        .atoms
+        .get(pos)
+        .map_or(0, |atom| atom.leading_ws.matches('\n').count());
+    1 + newlines + own_newlines
+}
+
+/*
+ * The atom at which to insert the text after the line: the first
+ * atom whose leading whitespace takes it past that line.
+ */
+fn insertion_point(text: &ParseStruct, after_line: usize) -> usize {
+    let mut line = 1;
+    for (i, atom) in text.atoms.iter().enumerate() {
+        line += atom.leading_ws.matches('\n').count();
+        if line > after_line {
+            return i;
+        }
+    }
+    text.atoms.len()
+}
+
+/// The line of the first token of the hunk, as the diff has it
+fn expected_line(hunk: &Hunk) -> usize {
+    let blank_lines = hunk
+        .source_lines()
+        .iter()
+        .take_while(|line| line.value.trim().is_empty())
+        .count();
+    hunk.source_start.max(1) + blank_lines
+}
+
+impl Patcher {
+    pub fn new(options: PatchOptions) -> Self {
+        Patcher { options }
+    }
+
+    pub fn options(&self) -> &PatchOptions {
+        &self.options
+    }
+
+    /// Apply a single hunk to the text
+    pub fn apply_to_str(&self, original: &str, hunk: &Hunk) -> Result<Applied, PatchError> {
+        self.apply_hunks(original, std::slice::from_ref(hunk))
+    }
+
+    /// Apply all the hunks for a file to its text, one after another
+    pub fn apply_hunks(&self, original: &str, hunks: &[Hunk]) -> Result<Applied, PatchError> {
+        let applied = self.try_apply_hunks(original, hunks);
+        match applied.hunks.iter().position(|h| !h.status.is_applied()) {
+            Some(i) => Err(PatchError::ContextNotFound {
+                hunk: i + 1,
+                line: hunks[i].source_start,
+            }),
+            None => Ok(applied),
+        }
+    }
+
+    /// Apply the hunks which can be applied, recording what became of each
+    pub fn try_apply_hunks(&self, original: &str, hunks: &[Hunk]) -> Applied {
+        let mut src_file = parse_string_with(original, self.options.tokenizer);
+        let mut applied_hunks = vec![];
+        /* the lines added by the hunks so far move the ones that follow */
+        let mut line_delta = 0;
+        for (i, hunk) in hunks.iter().enumerate() {
+            let expected = expected_line(hunk) as isize + line_delta;
+            let lines_added = hunk.target_length as isize - hunk.source_length as isize;
+            match self.apply_hunk(&src_file, hunk, i + 1, line_delta) {
+                Some((_, applied)) if applied.similarity < self.options.min_similarity => {
+                    applied_hunks.push(AppliedHunk {
+                        status: HunkStatus::Failed,
+                        ..applied
+                    });
+                }
+                Some((out_file, mut applied)) => {
+                    applied.offset = applied.line as isize - expected;
+                    line_delta += lines_added;
+                    src_file = out_file;
+                    applied_hunks.push(applied);
+                }
+                None => {
+                    let applied = match (self.find_applied(&src_file, hunk), self.options.merge) {
+                        (Some(applied), _) => {
+                            line_delta += lines_added;
+                            applied
+                        }
+                        (None, Some(style)) => {
+                            let (out_file, applied, lines_added) =
+                                self.merge_hunk(&src_file, hunk, expected.max(1) as usize, style);
+                            line_delta += lines_added;
+                            src_file = out_file;
+                            applied
+                        }
+                        (None, None) => {
+                            AppliedHunk::unapplied(HunkStatus::Failed, expected.max(1) as usize)
+                        }
+                    };
+                    applied_hunks.push(applied);
+                }
+            }
+        }
+        Applied {
+            text: parse_struct2str(&src_file),
+            hunks: applied_hunks,
+        }
+    }
+
+    /*
+     * Like try_apply_hunks, but for each hunk the places where its context
+     * is are given to the choose function, which tells where to apply it,
+     * to skip it, or to ask again with an edited hunk.
+     */
+    pub fn choose_hunks(
+        &self,
+        original: &str,
+        hunks: &[Hunk],
+        mut choose: impl FnMut(usize, &Hunk, &[HunkCandidate]) -> HunkChoice,
+    ) -> Applied {
+        let mut src_file = parse_string_with(original, self.options.tokenizer);
+        let mut applied_hunks = vec![];
+        let mut line_delta = 0;
+        for (i, hunk) in hunks.iter().enumerate() {
+            let mut hunk = hunk.clone();
+            let applied = loop {
+                let expected = expected_line(&hunk) as isize + line_delta;
+                let lines_added = hunk.target_length as isize - hunk.source_length as isize;
+                let (mut out_files, candidates): (Vec<_>, Vec<_>) = self
+                    .hunk_candidates(&src_file, &hunk, i + 1, line_delta)
+                    .into_iter()
+                    .unzip();
+                if candidates.is_empty() {
+                    if let Some(applied) = self.find_applied(&src_file, &hunk) {
+                        line_delta += lines_added;
+                        break applied;
+                    }
+                }
+                match choose(i, &hunk, &candidates) {
+                    HunkChoice::Apply(n) if n < candidates.len() => {
+                        line_delta += lines_added;
+                        src_file = out_files.swap_remove(n);
+                        break candidates[n].applied.clone();
+                    }
+                    HunkChoice::Apply(_) | HunkChoice::Skip => {
+                        let line = expected.max(1) as usize;
+                        break AppliedHunk::unapplied(HunkStatus::Skipped, line);
+                    }
+                    HunkChoice::Edit(edited) => hunk = edited,
+                }
+            };
+            applied_hunks.push(applied);
+        }
+        Applied {
+            text: parse_struct2str(&src_file),
+            hunks: applied_hunks,
+        }
+    }
+
+    /// Read the file the patch is for, apply it and write the result
+    pub fn apply_file_patch(
+        &self,
+        file: &FilePatch,
+        fs: &dyn FileSystem,
+    ) -> Result<AppliedFile, PatchError> {
+        self.apply_file_patch_with(file, fs, |original, hunks| {
+            self.try_apply_hunks(original, hunks)
+        })
+    }
+
+    /// Like apply_file_patch, choosing what to do with each hunk as choose_hunks does
+    pub fn apply_file_patch_interactive(
+        &self,
+        file: &FilePatch,
+        fs: &dyn FileSystem,
+        choose: impl FnMut(usize, &Hunk, &[HunkCandidate]) -> HunkChoice,
+    ) -> Result<AppliedFile, PatchError> {
+        self.apply_file_patch_with(file, fs, |original, hunks| {
+            self.choose_hunks(original, hunks, choose)
+        })
+    }
+
+    /*
+     * The file to read and the file to write. Unless git says the file
+     * is renamed, the two names are of the same file, e.g. for
+     * "diff -u foo.c.orig foo.c", so like GNU patch, the one which
+     * exists is patched in place, the new name if both do.
+     */
+    pub(crate) fn file_paths(
+        &self,
+        file: &FilePatch,
+        fs: &dyn FileSystem,
+    ) -> Result<(Option<String>, Option<String>), PatchError> {
+        let strip = self.options.strip;
+        let paths = (file.source_path(strip)?, file.target_path(strip)?);
+        Ok(match paths {
+            (Some(src_path), Some(dst_path)) if src_path != dst_path && !file.renamed => {
+                let path = if fs.exists(&dst_path) || !fs.exists(&src_path) {
+                    dst_path
+                } else {
+                    src_path
+                };
+                (Some(path.clone()), Some(path))
+            }
+            paths => paths,
+        })
+    }
+
+    fn apply_file_patch_with(
+        &self,
+        file: &FilePatch,
+        fs: &dyn FileSystem,
+        apply: impl FnOnce(&str, &[Hunk]) -> Applied,
+    ) -> Result<AppliedFile, PatchError> {
+        let (src_path, dst_path) = self.file_paths(file, fs)?;
+        let path = dst_path
+            .clone()
+            .or_else(|| src_path.clone())
+            .unwrap_or_default();
+        let io_err = |path: &str| {
+            let path = path.to_string();
+            move |error| PatchError::Io { path, error }
+        };
+        let original = match &src_path {
+            Some(src_path) => fs.read_to_string(src_path).map_err(io_err(src_path))?,
+            None if fs.exists(&path) => return Err(PatchError::FileExists { path }),
+            None => String::new(),
+        };
+        let applied = apply(&original, file.hunks());
+        let merged = |status: HunkStatus| {
+            status.is_applied() || matches!(status, HunkStatus::Conflict | HunkStatus::Skipped)
+        };
+        if !applied.hunks.iter().all(|h| merged(h.status)) {
+            return Err(PatchError::HunksFailed { path, applied });
+        }
+        self.write_patched(file, fs, &original, applied)
+    }
+
+    /*
+     * Like apply_file_patch, but when the hunks do not all apply, apply them
+     * to the file the diff was made against, found by the blob id of the git
+     * "index" header, and merge that into the file token by token, like
+     * "git apply -3" does line by line.
+     */
+    pub fn apply_file_patch_3way(
+        &self,
+        file: &FilePatch,
+        fs: &dyn FileSystem,
+        find_blob: impl FnOnce(&str) -> Option<String>,
+    ) -> Result<AppliedFile, PatchError> {
+        let strict = Patcher::new(PatchOptions {
+            merge: None,
+            ..self.options
+        });
+        let result = strict.apply_file_patch(file, fs);
+        let failed = matches!(result, Err(PatchError::HunksFailed { .. }));
+        let base = match &file.blob_ids {
+            Some((id, _)) if failed => find_blob(id),
+            _ => None,
+        };
+        let base = match base {
+            Some(base) => base,
+            None if failed && self.options.merge.is_some() => {
+                return self.apply_file_patch(file, fs)
+            }
+            None => return result,
+        };
+        let theirs = self.try_apply_hunks(&base, file.hunks());
+        if !theirs.hunks.iter().all(|h| h.status.is_applied()) {
+            return result;
+        }
+        let original = match self.file_paths(file, fs)?.0 {
+            Some(src_path) => fs
+                .read_to_string(&src_path)
+                .map_err(|error| PatchError::Io {
+                    path: src_path.clone(),
+                    error,
+                })?,
+            None => String::new(),
+        };
+        let opts = MergeOptions {
+            tokenizer: self.options.tokenizer,
+            style: self.options.merge.unwrap_or(MergeStyle::Merge),
+            labels: [
+                Some("ours".to_string()),
+                Some("base".to_string()),
+                Some("theirs".to_string()),
+            ],
+            ..Default::default()
+        };
+        let merged = merge3(&base, &original, &theirs.text, &opts);
+        /* which of the hunks the conflicts come from is not known, so they are all in them */
+        let markers: Vec<usize> = merged
+            .text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.starts_with("<<<<<<<") || line.starts_with(">>>>>>>"))
+            .map(|(i, _)| i + 1)
+            .collect();
+        let hunks = theirs
+            .hunks
+            .into_iter()
+            .map(|hunk| match (markers.first(), markers.last()) {
+                (Some(&first), Some(&last)) if merged.conflicts > 0 => AppliedHunk {
+                    end_line: last,
+                    ..AppliedHunk::unapplied(HunkStatus::Conflict, first)
+                },
+                _ => AppliedHunk {
+                    status: HunkStatus::Merged,
+                    ..hunk
+                },
+            })
+            .collect();
+        let applied = Applied {
+            text: merged.text,
+            hunks,
+        };
+        self.write_patched(file, fs, &original, applied)
+    }
+
+    /// Write the patched text, and the backup of the original
+    fn write_patched(
+        &self,
+        file: &FilePatch,
+        fs: &dyn FileSystem,
+        original: &str,
+        applied: Applied,
+    ) -> Result<AppliedFile, PatchError> {
+        let (src_path, dst_path) = self.file_paths(file, fs)?;
+        let path = dst_path
+            .clone()
+            .or_else(|| src_path.clone())
+            .unwrap_or_default();
+        let io_err = |path: &str| {
+            let path = path.to_string();
+            move |error| PatchError::Io { path, error }
+        };
+        if let (true, Some(src_path)) = (self.options.backup, &src_path) {
+            let backup_path = format!("{}.orig", src_path);
+            fs.write(&backup_path, original)
+                .map_err(io_err(&backup_path))?;
+        }
+        let operation = match (src_path, dst_path) {
+            (Some(src_path), None) => {
+                /* like GNU patch, keep the file if the hunks did not remove all of it */
+                if applied.text.trim().is_empty() {
+                    fs.delete(&src_path).map_err(io_err(&src_path))?;
+                    FileOperation::Removed
+                } else {
+                    fs.write(&src_path, &applied.text)
+                        .map_err(io_err(&src_path))?;
+                    FileOperation::Modified
+                }
+            }
+            (Some(src_path), Some(dst_path)) if src_path != dst_path => {
+                fs.rename(&src_path, &dst_path).map_err(io_err(&src_path))?;
+                fs.write(&dst_path, &applied.text)
+                    .map_err(io_err(&dst_path))?;
+                FileOperation::Renamed { from: src_path }
+            }
+            (src_path, _) => {
+                fs.write(&path, &applied.text).map_err(io_err(&path))?;
+                if src_path.is_some() {
+                    FileOperation::Modified
+                } else {
+                    FileOperation::Created
+                }
+            }
+        };
+        if let Some(mode) = file
+            .new_mode
+            .filter(|_| operation != FileOperation::Removed)
+        {
+            fs.set_mode(&path, mode).map_err(io_err(&path))?;
+        }
+        Ok(AppliedFile {
+            path,
+            operation,
+            applied,
+        })
+    }
+
+    /// Apply the patches to all the files, stopping at the first failure
+    pub fn apply_patchset(
+        &self,
+        patchset: &PatchSet,
+        fs: &dyn FileSystem,
+    ) -> Result<Vec<AppliedFile>, PatchError> {
+        patchset
+            .files()
+            .iter()
+            .map(|file| self.apply_file_patch(file, fs))
+            .collect()
+    }
+
+    /// One side of the hunk, with the indentation of the text it goes into
+    fn native_side(
+        &self,
+        hunk: &Hunk,
+        lines: &[unidiff::Line],
+        no_newline: bool,
+        text: &ParseStruct,
+        pos: usize,
+    ) -> String {
+        let side = parse_hunk_side(lines, false, no_newline, self.options.tokenizer).parse;
+        let mut atoms = side.atoms.clone();
+        if self.options.ws == WsPolicy::Infer {
+            let patch_style =
+                indent::IndentStyle::infer(&parse_string(&join_lines(&hunk.diff_lines())));
+            let target_style = indent::IndentStyle::infer(text);
+            /* nothing of it is known to be in the text, so it is all placed anew */
+            let origin = vec![None; atoms.len()];
+            indent::reindent_inserted(
+                &mut atoms,
+                &side,
+                &origin,
+                text,
+                pos,
+                &patch_style,
+                &target_style,
+            );
+        }
+        let side = parse_struct2str(&ParseStruct { atoms });
+        match side.strip_prefix('\n') {
+            Some(side) => format!("{}\n", side),
+            None if side.is_empty() => side,
+            None => format!("{}\n", side),
+        }
+    }
+
+    /*
+     * Put the hunk between the conflict markers, around the lines where
+     * the most of its context matched, or at the line where it should be
+     * if none did. Returns the text, and how many lines were added.
+     */
+    fn merge_hunk(
+        &self,
+        src_file: &ParseStruct,
+        hunk: &Hunk,
+        expected: usize,
+        style: MergeStyle,
+    ) -> (ParseStruct, AppliedHunk, isize) {
+        let markers = hunk.no_newline;
+        let needle = parse_hunk_side(
+            &hunk.source_lines(),
+            false,
+            markers.source,
+            self.options.tokenizer,
+        );
+        let (pos, matched) = best_partial_match(&needle.parse.atoms, src_file);
+        let text = parse_struct2str(src_file);
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        /* the lines of the text in the conflict, from first to before end */
+        let (first, end) = if matched > 0 {
+            let last_atom = (pos + needle.parse.atoms.len()).min(src_file.atoms.len()) - 1;
+            let first = line_of_atom(src_file, pos).min(lines.len());
+            (
+                first,
+                line_of_atom(src_file, last_atom).min(lines.len()) + 1,
+            )
+        } else {
+            let first = expected.min(lines.len() + 1);
+            (first, first)
+        };
+        let mut conflict = String::from("<<<<<<<\n");
+        for line in &lines[first - 1..end - 1] {
+            conflict.push_str(line);
+            if !line.ends_with('\n') {
+                conflict.push('\n');
+            }
+        }
+        if style == MergeStyle::Diff3 {
+            conflict.push_str("|||||||\n");
+            conflict.push_str(&self.native_side(
+                hunk,
+                &hunk.source_lines(),
+                markers.source,
+                src_file,
+                pos,
+            ));
+        }
+        conflict.push_str("=======\n");
+        conflict.push_str(&self.native_side(
+            hunk,
+            &hunk.target_lines(),
+            markers.target,
+            src_file,
+            pos,
+        ));
+        conflict.push_str(">>>>>>>\n");
+        let conflict_lines = conflict.matches('\n').count();
+        let out = format!(
+            "{}{}{}",
+            lines[..first - 1].concat(),
+            conflict,
+            lines[end - 1..].concat()
+        );
+        /* with nothing matched, no atoms are in the conflict: it is just before this one */
+        let token_pos = if matched > 0 {
+            pos
+        } else {
+            insertion_point(src_file, first - 1)
+        };
+        let applied = AppliedHunk {
+            token_pos,
+            token_end: token_pos + matched,
+            end_line: first + conflict_lines - 1,
+            offset: first as isize - expected as isize,
+            ..AppliedHunk::unapplied(HunkStatus::Conflict, first)
+        };
+        (
+            parse_string_with(&out, self.options.tokenizer),
+            applied,
+            conflict_lines as isize - (end - first) as isize,
+        )
+    }
+
+    /// Where the target side of the hunk is in the text, if its context is not
+    fn find_applied(&self, src_file: &ParseStruct, hunk: &Hunk) -> Option<AppliedHunk> {
+        let dst_side = parse_hunk_side(&hunk.target_lines(), false, false, self.options.tokenizer);
+        if dst_side.parse.atoms.is_empty() {
+            return None;
+        }
+        let pos = find_needle(&dst_side.parse.atoms, &src_file.atoms, false)?;
+        let line = line_of_atom(src_file, pos);
+        Some(AppliedHunk::unapplied(HunkStatus::AlreadyApplied, line))
+    }
+
+    /// The hunk applied at each of the places where its context is, with enough similarity
+    fn hunk_candidates(
+        &self,
+        src_file: &ParseStruct,
+        hunk: &Hunk,
+        hunk_no: usize,
+        line_delta: isize,
+    ) -> Vec<(ParseStruct, HunkCandidate)> {
+        let opts = &self.options;
+        let expected = expected_line(hunk) as isize + line_delta;
+        let after_line = self.insertion_line(hunk, line_delta);
+        let needle = parse_hunk_side(&hunk.source_lines(), false, false, opts.tokenizer).parse;
+        let positions: Vec<usize> = match after_line {
+            Some(line) => vec![insertion_point(src_file, line)],
+            None => (0..src_file.atoms.len())
+                .filter(|&p| src_file.atoms[p..].starts_with(&needle.atoms))
+                .collect(),
+        };
+        let mut candidates = vec![];
+        for p in positions {
+            let (out_file, mut applied) =
+                match self.apply_hunk_at(src_file, hunk, hunk_no, p, after_line) {
+                    Some(result) => result,
+                    None => continue,
+                };
+            if applied.similarity < opts.min_similarity {
+                continue;
+            }
+            applied.offset = applied.line as isize - expected;
+            /* the atoms after the match are the same in both */
+            let rest = src_file.atoms.len() - applied.token_end;
+            let old = ParseStruct {
+                atoms: src_file.atoms[p..applied.token_end].to_vec(),
+            };
+            let new = ParseStruct {
+                atoms: out_file.atoms[p..out_file.atoms.len().saturating_sub(rest).max(p)].to_vec(),
+            };
+            candidates.push((out_file, HunkCandidate { applied, old, new }));
+        }
+        candidates
+    }
+
+    /*
+     * For a hunk without context, as "diff -U0" makes them, the line after
+     * which it goes, moved by the lines the hunks before it added; None
+     * for the hunks whose context tells where they go.
+     */
+    fn insertion_line(&self, hunk: &Hunk, line_delta: isize) -> Option<usize> {
+        let src_side = parse_hunk_side(&hunk.source_lines(), false, false, self.options.tokenizer);
+        if src_side.parse.atoms.is_empty() {
+            Some((hunk.source_start as isize + line_delta).max(0) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn apply_hunk(
+        &self,
+        src_file: &ParseStruct,
+        hunk: &Hunk,
+        hunk_no: usize,
+        line_delta: isize,
+    ) -> Option<(ParseStruct, AppliedHunk)> {
+        let opts = &self.options;
+        let src_side = parse_hunk_side(
+            &hunk.source_lines(),
+            false,
+            hunk.no_newline.source,
+            opts.tokenizer,
+        );
+        let after_line = self.insertion_line(hunk, line_delta);
+        let find_pos = match after_line {
+            Some(line) => Some(insertion_point(src_file, line)),
+            None => find_needle(&src_side.parse.atoms, &src_file.atoms, false),
+        };
+        debug!(
+            "hunk #{}: found at {:?} (of {} tokens)",
+            hunk_no,
+            &find_pos,
+            src_side.parse.atoms.len()
+        );
+        match find_pos {
+            Some(p) => self.apply_hunk_at(src_file, hunk, hunk_no, p, after_line),
+            None => {
+                if log_enabled!(Level::Trace) {
+                    find_needle(&src_side.parse.atoms, &src_file.atoms, true);
+                    trace!("File:'{}'", parse_struct2str(src_file));
+                }
+                None
+            }
+        }
+    }
+
+    /*
+     * Apply the hunk with its context matched at the atom p of the text,
+     * or for a hunk without context, insert it there, after the line given.
+     * None if the edits of the hunk do not fit the text there.
+     */
+    fn apply_hunk_at(
+        &self,
+        src_file: &ParseStruct,
+        hunk: &Hunk,
+        hunk_no: usize,
+        p: usize,
+        after_line: Option<usize>,
+    ) -> Option<(ParseStruct, AppliedHunk)> {
+        let opts = &self.options;
+        let markers = hunk.no_newline;
+        /*
+         * At the very beginning of the file, the newline we put in front
+         * of the first line of the hunk does not exist.
+         */
+        let at_start = after_line.map_or(p == 0, |line| line == 0);
+        let src_side = parse_hunk_side(
+            &hunk.source_lines(),
+            at_start,
+            markers.source,
+            opts.tokenizer,
+        );
+        let dst_side = parse_hunk_side(
+            &hunk.target_lines(),
+            at_start,
+            markers.target,
+            opts.tokenizer,
+        );
+        let src = &src_side.parse;
+        let dst = &dst_side.parse;
+
+        if log_enabled!(Level::Debug) {
+            debug!("hunk #{}:\n{}", hunk_no, format_diff_c(dst, src.diff(dst)));
+        }
+        if log_enabled!(Level::Trace) {
+            trace!("hunk #{} edits:\n{}", hunk_no, format_diff(src.diff(dst)));
+        }
+        let diff = src.diff(dst);
+
+        let mut out_file = ParseStruct {
+            atoms: src_file.atoms[0..p].to_vec(),
+        };
+        let ws_only_hunk = src.atoms == dst.atoms;
+        let mut origin = vec![];
+        let src_skip = apply_patch(
+            &mut out_file,
+            &mut origin,
+            src_file,
+            p,
+            dst,
+            diff,
+            opts.patch_ws(),
+        )?;
+
+        let patch_style =
+            indent::IndentStyle::infer(&parse_string(&join_lines(&hunk.diff_lines())));
+        let target_style = indent::IndentStyle::infer(src_file);
+        if opts.ws == WsPolicy::Infer {
+            indent::reindent_inserted(
+                &mut out_file.atoms[p..],
+                dst,
+                &origin,
+                src_file,
+                p,
+                &patch_style,
+                &target_style,
+            );
+        }
+        /*
+         * A hunk which only changes the whitespace has the same atoms
+         * on both sides, so they all are matched, one to one, in the file.
+         */
+        let ws_deltas = ws_only_hunk && opts.ws_only_edits && opts.ws != WsPolicy::TakePatch;
+        if ws_deltas {
+            for (i, (src_atom, dst_atom)) in src.atoms.iter().zip(&dst.atoms).enumerate() {
+                out_file.atoms[p + i].leading_ws = indent::apply_ws_delta(
+                    &src_atom.leading_ws,
+                    &dst_atom.leading_ws,
+                    &src_file.atoms[p + i].leading_ws,
+                    &patch_style,
+                    &target_style,
+                );
+            }
+        }
+        let mut rest = src_file.atoms[p + src_skip..].to_vec();
+        if let (Some(line), Some(next_atom), false) =
+            (after_line, rest.first_mut(), dst.atoms.is_empty())
+        {
+            if line == 0 {
+                /* the first line of the file now comes after the inserted ones */
+                next_atom.leading_ws.insert(0, '\n');
+            } else {
+                /*
+                 * The newlines of the blank lines up to the line to insert
+                 * after, if any, go before the inserted text.
+                 */
+                let newlines_before: usize = src_file.atoms[..p]
+                    .iter()
+                    .map(|atom| atom.leading_ws.matches('\n').count())
+                    .sum();
+                let keep = line - 1 - newlines_before;
+                let split = next_atom
+                    .leading_ws
+                    .match_indices('\n')
+                    .nth(keep)
+                    .map_or(0, |(i, _)| i);
+                let blank_lines: String = next_atom.leading_ws.drain(..split).collect();
+                out_file.atoms[p].leading_ws.insert_str(0, &blank_lines);
+            }
+        }
+        let rest = &rest[..];
+        /*
+         * A creation of the file, or a "\ No newline at end of file" marker
+         * on either side, means the hunk ends where the file ends, so what
+         * trails its last token is dictated by the target side of the hunk.
+         * Unless there are more tokens in the file, in which case this is
+         * not the end of it.
+         */
+        let at_eof = markers.any() || (hunk.source_start == 0 && hunk.source_length == 0);
+        if at_eof && rest.iter().all(|atom| atom.token_value.is_empty()) {
+            if !dst_side.trailing_ws.is_empty() {
+                out_file.atoms.push(TextAtom {
+                    token_value: String::new(),
+                    token_uuid: String::new(),
+                    leading_ws: dst_side.trailing_ws.clone(),
+                });
+            }
+        } else {
+            /* the trailing whitespace of the last line is in the next atom */
+            if let (true, Some(next_atom)) = (ws_deltas, rest.first()) {
+                out_file.atoms.push(TextAtom {
+                    leading_ws: indent::apply_ws_delta(
+                        &src_side.trailing_ws,
+                        &dst_side.trailing_ws,
+                        &next_atom.leading_ws,
+                        &patch_style,
+                        &target_style,
+                    ),
+                    ..next_atom.clone()
+                });
+                out_file.atoms.extend_from_slice(&rest[1..]);
+            } else {
+                out_file.atoms.extend_from_slice(rest);
+            }
+        }
+        let same_ws = src
+            .atoms
+            .iter()
+            .zip(&src_file.atoms[p..])
+            .filter(|(a, b)| a.leading_ws == b.leading_ws)
+            .count();
+        let ws_adjusted = dst
+            .atoms
+            .iter()
+            .zip(&out_file.atoms[p..])
+            .filter(|(a, b)| a.leading_ws != b.leading_ws)
+            .count();
+        let similarity = if src.atoms.is_empty() {
+            1.0
+        } else {
+            same_ws as f64 / src.atoms.len() as f64
+        };
+        /* with nothing matched, the hunk is where it is inserted */
+        let (line, end_line) = match after_line {
+            Some(line) => (line.max(1), line.max(1)),
+            None => (
+                line_of_atom(src_file, p),
+                line_of_atom(src_file, (p + src_skip).max(p + 1) - 1),
+            ),
+        };
+        let applied = AppliedHunk {
+            status: if same_ws == src.atoms.len() {
+                HunkStatus::Applied
+            } else {
+                HunkStatus::Fuzzed
+            },
+            token_pos: p,
+            token_end: p + src_skip,
+            line,
+            end_line,
+            offset: 0,
+            similarity,
+            ws_adjusted,
+        };
+        Some((out_file, applied))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{MemoryFileSystem, OverlayFileSystem};
+
+    fn tagged(text: &str, uuids: &[&str]) -> ParseStruct {
+        let mut parse = parse_string(text);
+        for (atom, uuid) in parse.atoms.iter_mut().zip(uuids) {
+            atom.token_uuid = uuid.to_string();
+        }
+        parse
+    }
+
+    /// The kinds of the per-atom edits between the two sides
+    fn edit_kinds(left: &ParseStruct, right: &ParseStruct) -> Vec<&'static str> {
+        match left.diff(right) {
+            edit::Edit::Copy(_) => vec![],
+            edit::Edit::Change(EditedParseStruct { atoms }) => match atoms {
+                edit::Edit::Copy(_) => vec![],
+                edit::Edit::Change(diff) => diff
+                    .iter()
+                    .map(|edit| match edit {
+                        collection::Edit::Copy(_) => "copy",
+                        collection::Edit::Insert(_) => "insert",
+                        collection::Edit::Remove(_) => "remove",
+                        collection::Edit::Change(EditedTextAtom { token_value, .. }) => {
+                            if token_value.is_copy() {
+                                "change-ws"
+                            } else {
+                                "change-value"
+                            }
+                        }
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn apply(
+        file: &ParseStruct,
+        p: usize,
+        left: &ParseStruct,
+        right: &ParseStruct,
+        ws: WsPolicy,
+    ) -> String {
+        try_apply(file, p, left, right, ws).unwrap()
+    }
+
+    fn try_apply(
+        file: &ParseStruct,
+        p: usize,
+        left: &ParseStruct,
+        right: &ParseStruct,
+        ws: WsPolicy,
+    ) -> Option<String> {
+        let opts = PatchOptions {
+            ws,
+            ..Default::default()
+        };
+        let mut out_file = ParseStruct {
+            atoms: file.atoms[0..p].to_vec(),
+        };
+        let mut origin = vec![];
+        let src_skip = apply_patch(
+            &mut out_file,
+            &mut origin,
+            file,
+            p,
+            right,
+            left.diff(right),
+            opts.patch_ws(),
+        )?;
+        assert_eq!(origin.len(), right.atoms.len());
+        out_file
+            .atoms
+            .extend_from_slice(&file.atoms[p + src_skip..]);
+        Some(parse_struct2str(&out_file))
+    }
+
+    #[test]
+    fn apply_identical() {
+        let file = parse_string("int a;\nint b;\n");
+        let left = parse_string("\nint b;");
+        assert_eq!(edit_kinds(&left, &left), Vec::<&str>::new());
+        let out = apply(&file, 3, &left, &left, WsPolicy::Infer);
+        assert_eq!(out, "int a;\nint b;\n");
+    }
+
+    #[test]
+    fn apply_copy_and_insert() {
+        let file = parse_string("foo(a,  b);\n");
+        let left = parse_string("foo(a, b);");
+        let right = parse_string("foo(a, x, b);");
+        assert!(edit_kinds(&left, &right).contains(&"copy"));
+        assert!(edit_kinds(&left, &right).contains(&"insert"));
+        let out = apply(&file, 0, &left, &right, WsPolicy::KeepTarget);
+        assert_eq!(out, "foo(a, x,  b);\n");
+    }
+
+    #[test]
+    fn apply_remove() {
+        let file = parse_string("foo(a, x, b);\n");
+        let left = parse_string("foo(a, x, b);");
+        let right = parse_string("foo(a, b);");
+        assert!(edit_kinds(&left, &right).contains(&"remove"));
+        let out = apply(&file, 0, &left, &right, WsPolicy::KeepTarget);
+        assert_eq!(out, "foo(a, b);\n");
+    }
+
+    #[test]
+    fn apply_change_ws() {
+        let file = parse_string("a\n\tb;\n");
+        let left = parse_string("a\n  b;");
+        let right = parse_string("a\n    b;");
+        assert_eq!(edit_kinds(&left, &right), vec!["copy", "change-ws", "copy"]);
+        assert_eq!(
+            apply(&file, 0, &left, &right, WsPolicy::KeepTarget),
+            "a\n\tb;\n"
+        );
+        assert_eq!(
+            apply(&file, 0, &left, &right, WsPolicy::TakePatch),
+            "a\n    b;\n"
+        );
+    }
+
+    #[test]
+    fn apply_change_value() {
+        let file = parse_string("int  foo = 1;\n");
+        let left = tagged("int foo = 1;", &["", "id-1"]);
+        let right = tagged("int bar = 1;", &["", "id-1"]);
+        assert_eq!(
+            edit_kinds(&left, &right),
+            vec!["copy", "change-value", "copy", "copy", "copy"]
+        );
+        let out = apply(&file, 0, &left, &right, WsPolicy::KeepTarget);
+        assert_eq!(out, "int  bar = 1;\n");
+    }
+
+    #[test]
+    fn apply_change_value_mismatch() {
+        let file = parse_string("int baz = 1;\n");
+        let left = tagged("int foo = 1;", &["", "id-1"]);
+        let right = tagged("int bar = 1;", &["", "id-1"]);
+        assert_eq!(
+            try_apply(&file, 0, &left, &right, WsPolicy::KeepTarget),
+            None
+        );
+        /* past the end of the file */
+        let file = parse_string("int");
+        assert_eq!(
+            try_apply(&file, 0, &left, &right, WsPolicy::KeepTarget),
+            None
+        );
+    }
+
+    fn hunks(diff: &str) -> Vec<Hunk> {
+        PatchSet::parse(diff).unwrap().files()[0].hunks().to_vec()
+    }
+
+    #[test]
+    fn hunk_ws_policies() {
+        /* "1" is copied, the hunk respaces "x" and inserts "d();" */
+        let file = "if (a) {\n\tb( 1);\n\tc( x);\n}\n";
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -1,4 +1,5 @@\n if (a) {\n     b(1);\n\
+                    -    c( x);\n+    c(x);\n+    d();\n }\n";
+        let apply = |ws| {
+            let patcher = Patcher::new(PatchOptions {
+                ws,
+                ..Default::default()
+            });
+            patcher.apply_hunks(file, &hunks(diff)).unwrap().text
+        };
+        assert_eq!(
+            apply(WsPolicy::KeepTarget),
+            "if (a) {\n\tb( 1);\n\tc( x);\n    d();\n}\n"
+        );
+        assert_eq!(
+            apply(WsPolicy::TakePatch),
+            "if (a) {\n    b(1);\n    c(x);\n    d();\n}\n"
+        );
+        assert_eq!(
+            apply(WsPolicy::Infer),
+            "if (a) {\n\tb( 1);\n\tc( x);\n\td();\n}\n"
+        );
+    }
+
+    #[test]
+    fn hunk_ws_only_edits() {
+        /* a level deeper in the 2-column patch is a tab deeper in the file */
+        let file = "int f()\n{\n\tif (a)\n\tb();\n}\n";
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -2,4 +2,4 @@\n {\n   if (a)\n-  b();\n+    b();\n }\n";
+        let apply = |ws_only_edits| {
+            let patcher = Patcher::new(PatchOptions {
+                ws_only_edits,
+                ..Default::default()
+            });
+            patcher.apply_hunks(file, &hunks(diff)).unwrap().text
+        };
+        assert_eq!(apply(false), file);
+        assert_eq!(apply(true), "int f()\n{\n\tif (a)\n\t\tb();\n}\n");
+    }
+
+    #[test]
+    fn hunk_no_newline_markers() {
+        let patcher = Patcher::default();
+        /* the newline is added at the end of the file */
+        let diff =
+            "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n a;\n-b;\n\\ No newline at end of file\n+b;\n";
+        let hunks_added = hunks(diff);
+        assert!(hunks_added[0].no_newline.source && !hunks_added[0].no_newline.target);
+        let applied = patcher.apply_hunks("a;\nb;", &hunks_added).unwrap();
+        assert_eq!(applied.text, "a;\nb;\n");
+
+        /* and removed */
+        let diff =
+            "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n a;\n-b;\n+b;\n\\ No newline at end of file\n";
+        let hunks_removed = hunks(diff);
+        assert!(!hunks_removed[0].no_newline.source && hunks_removed[0].no_newline.target);
+        let applied = patcher.apply_hunks("a;\nb;\n", &hunks_removed).unwrap();
+        assert_eq!(applied.text, "a;\nb;");
+    }
+
+    #[test]
+    fn hunk_statuses() {
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -1,2 +1,2 @@\n int a;\n-int b;\n+int x;\n\
+                    @@ -3,2 +3,2 @@\n int c;\n-int d;\n+int y;\n@@ -5,2 +5,2 @@\n int e;\n-int f;\n+int z;\n";
+        let original = "int a;\nint b;\nint  c;\nint y;\nint e;\nint g;\n";
+        let applied = Patcher::default().try_apply_hunks(original, &hunks(diff));
+        let statuses: Vec<HunkStatus> = applied.hunks.iter().map(|h| h.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                HunkStatus::Applied,
+                HunkStatus::AlreadyApplied,
+                HunkStatus::Failed
+            ]
+        );
+        assert_eq!(applied.hunks[0].line, 1);
+        assert_eq!(applied.hunks[0].end_line, 2);
+        assert_eq!(applied.hunks[1].line, 3);
+        assert_eq!(
+            applied.text,
+            "int a;\nint x;\nint  c;\nint y;\nint e;\nint g;\n"
+        );
+        assert!(Patcher::default()
+            .apply_hunks(original, &hunks(diff))
+            .is_err());
+    }
+
+    #[test]
+    fn hunk_without_context() {
+        let patcher = Patcher::default();
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -2,0 +3,1 @@\n+new;\n";
+        let applied = patcher.apply_hunks("x;\ny;\nz;\n", &hunks(diff)).unwrap();
+        assert_eq!(applied.text, "x;\ny;\nnew;\nz;\n");
+        assert_eq!((applied.hunks[0].line, applied.hunks[0].offset), (2, 0));
+
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -0,0 +1,1 @@\n+new;\n";
+        let applied = patcher.apply_hunks("x;\ny;\n", &hunks(diff)).unwrap();
+        assert_eq!(applied.text, "new;\nx;\ny;\n");
+
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -2,0 +3,1 @@\n+new;\n";
+        let applied = patcher.apply_hunks("x;\n\ny;\n", &hunks(diff)).unwrap();
+        assert_eq!(applied.text, "x;\n\nnew;\ny;\n");
+
+        /* the line numbers of the second are after the first has been applied */
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -1,0 +2,2 @@\n+a;\n+b;\n@@ -3,0 +6,1 @@\n+c;\n";
+        let applied = patcher.apply_hunks("x;\ny;\nz;\n", &hunks(diff)).unwrap();
+        assert_eq!(applied.text, "x;\na;\nb;\ny;\nz;\nc;\n");
+    }
+
+    #[test]
+    fn hunk_merge_conflict() {
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -1,2 +1,2 @@\n int a;\n-int b;\n+int x;\n\
+                    @@ -5,2 +5,2 @@\n int e;\n-int f;\n+int z;\n";
+        let original = "int a;\nint b;\nint c;\nint d;\nint e;\nint g;\nint h;\n";
+        let patcher = Patcher::new(PatchOptions {
+            merge: Some(MergeStyle::Diff3),
+            ..Default::default()
+        });
+        let applied = patcher.try_apply_hunks(original, &hunks(diff));
+        assert_eq!(applied.hunks[0].status, HunkStatus::Applied);
+        assert_eq!(applied.hunks[1].status, HunkStatus::Conflict);
+        assert_eq!((applied.hunks[1].line, applied.hunks[1].end_line), (5, 14));
+        assert_eq!(
+            applied.text,
+            "int a;\nint x;\nint c;\nint d;\n<<<<<<<\nint e;\nint g;\n\
+             |||||||\nint e;\nint f;\n=======\nint e;\nint z;\n>>>>>>>\nint h;\n"
+        );
+    }
+
+    #[test]
+    fn hunk_merge_conflict_nothing_matched() {
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -3,1 +3,1 @@\n-zzz qqq;\n+yyy;\n";
+        let patcher = Patcher::new(PatchOptions {
+            merge: Some(MergeStyle::Merge),
+            ..Default::default()
+        });
+        let applied = patcher.try_apply_hunks("int a;\nint b;\nint c;\n", &hunks(diff));
+        let hunk = &applied.hunks[0];
+        assert_eq!(hunk.status, HunkStatus::Conflict);
+        assert_eq!(
+            applied.text,
+            "int a;\nint b;\n<<<<<<<\n=======\nyyy;\n>>>>>>>\nint c;\n"
+        );
+        /* the third "int", the conflict being written before it */
+        assert_eq!((hunk.token_pos, hunk.token_end), (6, 6));
+        assert_eq!((hunk.line, hunk.end_line), (3, 6));
+    }
+
+    #[test]
+    fn file_patch_3way() {
+        let diff = "diff --git a/f.c b/f.c\nindex 1234567..89abcde 100644\n--- a/f.c\n+++ b/f.c\n\
+                    @@ -1,3 +1,3 @@\n f(a);\n-g(b);\n+g(x);\n h(c);\n";
+        let patch = PatchSet::parse(diff).unwrap();
+        let file = &patch.files()[0];
+        assert_eq!(
+            file.blob_ids,
+            Some(("1234567".to_string(), "89abcde".to_string()))
+        );
+        let fs = MemoryFileSystem::new();
+        fs.insert("f.c", "f(a); g(b);\nh(y);\n");
+        let patcher = Patcher::default();
+        assert!(patcher.apply_file_patch(file, &fs).is_err());
+        let base = |id: &str| {
+            Some(id)
+                .filter(|&id| id == "1234567")
+                .map(|_| "f(a);\ng(b);\nh(c);\n".to_string())
+        };
+        let applied = patcher.apply_file_patch_3way(file, &fs, base).unwrap();
+        assert_eq!(applied.applied.hunks[0].status, HunkStatus::Merged);
+        assert_eq!(fs.read_to_string("f.c").unwrap(), "f(a); g(x);\nh(y);\n");
+    }
+
+    #[test]
+    fn file_patch_3way_one_line() {
+        /* the ranges of one line as git writes them, with the next file right after */
+        let diff = "diff --git a/f.c b/f.c\nindex 1234567..89abcde 100644\n--- a/f.c\n+++ b/f.c\n\
+                    @@ -1 +1 @@\n-f(a, b);\n+f(a, x);\n\
+                    diff --git a/g.c b/g.c\nindex 7654321..edcba98 100644\n--- a/g.c\n+++ b/g.c\n\
+                    @@ -1 +1 @@\n-g();\n+h();\n";
+        let patch = PatchSet::parse(diff).unwrap();
+        let file = &patch.files()[0];
+        assert_eq!(file.hunks()[0].diff_lines().len(), 2);
+        let fs = MemoryFileSystem::new();
+        fs.insert("f.c", "if (y) {\n  f(q, b);\n}\n");
+        let patcher = Patcher::default();
+        assert!(patcher.apply_file_patch(file, &fs).is_err());
+        let base = |id: &str| Some("f(a, b);\n".to_string()).filter(|_| id == "1234567");
+        let applied = patcher.apply_file_patch_3way(file, &fs, base).unwrap();
+        assert_eq!(applied.applied.hunks[0].status, HunkStatus::Merged);
+        assert_eq!(
+            fs.read_to_string("f.c").unwrap(),
+            "if (y) {\n  f(q, x);\n}\n"
+        );
+    }
+
+    #[test]
+    fn hunk_offset_and_fuzz() {
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -1,2 +1,2 @@\n int a;\n-int b;\n+int x;\n";
+        let applied = Patcher::default()
+            .apply_to_str("int q;\nint  a;\nint b;\n", &hunks(diff)[0])
+            .unwrap();
+        let hunk = &applied.hunks[0];
+        assert_eq!(hunk.status, HunkStatus::Fuzzed);
+        assert_eq!(hunk.offset, 1);
+        assert!(hunk.similarity < 1.0);
+    }
+
+    #[test]
+    fn choose_hunks_places() {
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -1,2 +1,2 @@\n a;\n-b;\n+B;\n\
+                    @@ -7,1 +7,1 @@\n-z;\n+Z;\n@@ -9,1 +9,1 @@\n-nope;\n+yes;\n";
+        let hunks = hunks(diff);
+        let edited = hunks[0].clone();
+        let mut asked = vec![];
+        let mut previews = vec![];
+        let applied = Patcher::default().choose_hunks(
+            "a;\nb;\nx;\na;\nb;\nz;\n",
+            &hunks,
+            |i, hunk, candidates| {
+                asked.push(
+                    candidates
+                        .iter()
+                        .map(|c| c.applied.line)
+                        .collect::<Vec<_>>(),
+                );
+                if let Some(candidate) = candidates.first() {
+                    previews.push((candidate.preview(false), candidate.preview(true)));
+                }
+                match i {
+                    0 => HunkChoice::Apply(1),
+                    1 => HunkChoice::Skip,
+                    _ if hunk.source_start == 9 => HunkChoice::Edit(edited.clone()),
+                    _ => HunkChoice::Apply(0),
+                }
+            },
+        );
+        assert_eq!(asked, [vec![1, 4], vec![6], vec![], vec![1]]);
+        assert_eq!(previews[0].0, "a;\n[-b-]{+B+};\n");
+        assert!(!previews[0].0.contains('\x1b'));
+        assert!(previews[0].1.contains("\x1b[32mB"));
+        assert_eq!(applied.text, "a;\nB;\nx;\na;\nB;\nz;\n");
+        let statuses: Vec<_> = applied.hunks.iter().map(|h| h.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                HunkStatus::Applied,
+                HunkStatus::Skipped,
+                HunkStatus::Applied
+            ]
+        );
+        assert_eq!(applied.hunks[0].offset, 3);
+    }
+
+    #[test]
+    fn choose_hunks_no_such_candidate() {
+        let diff =
+            "--- a/f.c\n+++ b/f.c\n@@ -1,1 +1,1 @@\n-a;\n+A;\n@@ -5,1 +5,1 @@\n-nope;\n+yes;\n";
+        let applied =
+            Patcher::default().choose_hunks("a;\nb;\n", &hunks(diff), |_, _, candidates| {
+                HunkChoice::Apply(candidates.len())
+            });
+        assert_eq!(applied.text, "a;\nb;\n");
+        let statuses: Vec<_> = applied.hunks.iter().map(|h| h.status).collect();
+        assert_eq!(statuses, [HunkStatus::Skipped, HunkStatus::Skipped]);
+    }
+
+    fn apply_diff(fs: &dyn FileSystem, diff: &str) -> Result<Vec<AppliedFile>, PatchError> {
+        let patchset = PatchSet::parse(diff).unwrap();
+        Patcher::default().apply_patchset(&patchset, fs)
+    }
+
+    #[test]
+    fn patchset_modify() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("foo.c", "int  a;\nint b;\nint c;\n");
+        let diff =
+            "--- a/foo.c\n+++ b/foo.c\n@@ -1,3 +1,3 @@\n int a;\n-int b;\n+int x;\n int c;\n";
+        let applied = apply_diff(&fs, diff).unwrap();
+        assert_eq!(applied[0].operation, FileOperation::Modified);
+        assert_eq!(
+            fs.get("foo.c").unwrap().contents,
+            "int  a;\nint x;\nint c;\n"
+        );
+    }
+
+    #[test]
+    fn patchset_create_and_remove() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("old.c", "int a;\n");
+        let diff = "diff --git a/new.sh b/new.sh\nnew file mode 100755\n--- /dev/null\n+++ b/new.sh\n@@ -0,0 +1,1 @@\n+echo hi\n\
+                    diff --git a/old.c b/old.c\ndeleted file mode 100644\n--- a/old.c\n+++ /dev/null\n@@ -1,1 +0,0 @@\n-int a;\n";
+        let applied = apply_diff(&fs, diff).unwrap();
+        assert_eq!(applied[0].operation, FileOperation::Created);
+        assert_eq!(applied[1].operation, FileOperation::Removed);
+        let new = fs.get("new.sh").unwrap();
+        assert_eq!(new.contents, "echo hi\n");
+        assert_eq!(new.mode, 0o755);
+        assert!(!fs.exists("old.c"));
+    }
+
+    #[test]
+    fn patchset_hunks_without_counts() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("old.c", "int a;\nint b;\nint c;\n");
+        let diff = "--- /dev/null\n+++ b/new.c\n@@ -0,0 +1 @@\n+int n;\n\
+                    --- a/old.c\n+++ b/old.c\n@@ -3 +3 @@\n-int c;\n+int x;\n";
+        let applied = apply_diff(&fs, diff).unwrap();
+        for (file, line) in applied.iter().zip([1, 3]) {
+            let hunk = &file.applied.hunks[0];
+            assert_eq!(hunk.status, HunkStatus::Applied);
+            assert_eq!((hunk.line, hunk.offset), (line, 0));
+        }
+        assert_eq!(fs.get("new.c").unwrap().contents, "int n;\n");
+        assert_eq!(
+            fs.get("old.c").unwrap().contents,
+            "int a;\nint b;\nint x;\n"
+        );
+    }
+
+    #[test]
+    fn patchset_create_existing() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("new.c", "int a;\n");
+        let diff = "--- /dev/null\n+++ b/new.c\n@@ -0,0 +1,1 @@\n+int a;\n";
+        match apply_diff(&fs, diff) {
+            Err(PatchError::FileExists { path }) => assert_eq!(path, "new.c"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn patchset_rename() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("old.c", "int a;\n");
+        let diff = "diff --git a/old.c b/new.c\nsimilarity index 50%\nrename from old.c\nrename to new.c\n\
+                    --- a/old.c\n+++ b/new.c\n@@ -1,1 +1,1 @@\n-int a;\n+int b;\n";
+        let applied = apply_diff(&fs, diff).unwrap();
+        assert_eq!(
+            applied[0].operation,
+            FileOperation::Renamed {
+                from: "old.c".to_string()
+            }
+        );
+        assert!(!fs.exists("old.c"));
+        assert_eq!(fs.get("new.c").unwrap().contents, "int b;\n");
+    }
+
+    #[test]
+    fn patchset_different_names() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("foo.c", "int a;\n");
+        fs.insert("foo.c.orig", "int a;\n");
+        let diff = "--- foo.c.orig\n+++ foo.c\n@@ -1,1 +1,1 @@\n-int a;\n+int b;\n";
+        let patchset = PatchSet::parse(diff).unwrap();
+        let patcher = Patcher::new(PatchOptions {
+            strip: 0,
+            ..Default::default()
+        });
+        let applied = patcher.apply_patchset(&patchset, &fs).unwrap();
+        assert_eq!(applied[0].operation, FileOperation::Modified);
+        assert_eq!(applied[0].path, "foo.c");
+        assert_eq!(fs.get("foo.c").unwrap().contents, "int b;\n");
+        assert_eq!(fs.get("foo.c.orig").unwrap().contents, "int a;\n");
+
+        /* without the new one, the old one is patched in place */
+        fs.delete("foo.c").unwrap();
+        patcher.apply_patchset(&patchset, &fs).unwrap();
+        assert!(!fs.exists("foo.c"));
+        assert_eq!(fs.get("foo.c.orig").unwrap().contents, "int b;\n");
+    }
+
+    #[test]
+    fn patchset_parent_dir() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("../../.bashrc", "ls\n");
+        let diff = "--- a/../../.bashrc\n+++ b/../../.bashrc\n@@ -1,1 +1,1 @@\n-ls\n+rm\n";
+        match apply_diff(&fs, diff) {
+            Err(PatchError::UnsafePath { path }) => assert_eq!(path, "../../.bashrc"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(fs.get("../../.bashrc").unwrap().contents, "ls\n");
+    }
+
+    #[test]
+    fn patchset_absolute_path() {
+        let fs = MemoryFileSystem::new();
+        let diff = "--- /dev/null\n+++ /etc/x\n@@ -0,0 +1,1 @@\n+x\n";
+        let patchset = PatchSet::parse(diff).unwrap();
+        let patcher = Patcher::new(PatchOptions {
+            strip: 0,
+            ..Default::default()
+        });
+        match patcher.apply_patchset(&patchset, &fs) {
+            Err(PatchError::UnsafePath { path }) => assert_eq!(path, "/etc/x"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(fs.files().is_empty());
+    }
+
+    #[test]
+    fn patchset_overlay() {
+        let base = MemoryFileSystem::new();
+        base.insert("old.c", "int a;\n");
+        let fs = OverlayFileSystem::new(&base);
+        let diff = "diff --git a/old.c b/new.c\nrename from old.c\nrename to new.c\n\
+                    --- a/old.c\n+++ b/new.c\n@@ -1,1 +1,1 @@\n-int a;\n+int b;\n";
+        apply_diff(&fs, diff).unwrap();
+        assert!(!fs.exists("old.c"));
+        assert_eq!(fs.read_to_string("new.c").unwrap(), "int b;\n");
+        assert_eq!(base.files().len(), 1);
+        assert_eq!(base.get("old.c").unwrap().contents, "int a;\n");
+    }
+}
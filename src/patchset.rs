@@ -0,0 +1,379 @@
+/*
+ * The unified diff as parsed by unidiff, along with the bits
+ * of it that unidiff does not keep.
+ */
+use crate::patch::PatchError;
+use std::ops::Deref;
+use std::path::{Component, Path};
+
+pub const DEV_NULL: &str = "/dev/null";
+
+/// The "\ No newline at end of file" markers seen within a hunk
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct NoNewlineMarkers {
+    pub(crate) source: bool,
+    pub(crate) target: bool,
+}
+
+impl NoNewlineMarkers {
+    fn mark_after(&mut self, line: &unidiff::Line) {
+        if line.is_removed() {
+            self.source = true;
+        } else if line.is_added() {
+            self.target = true;
+        } else if line.is_context() {
+            self.source = true;
+            self.target = true;
+        }
+    }
+
+    /// whether the hunk is known to end at the end of the file
+    pub(crate) fn any(&self) -> bool {
+        self.source || self.target
+    }
+}
+
+fn get_no_newline_markers(hunk: &unidiff::Hunk, diff_lines: &[&str]) -> NoNewlineMarkers {
+    let mut markers = NoNewlineMarkers::default();
+    let mut prev_line: Option<&unidiff::Line> = None;
+    for line in hunk.lines() {
+        if line.line_type == "\\" {
+            if let Some(prev) = prev_line {
+                markers.mark_after(prev);
+            }
+        }
+        prev_line = Some(line);
+    }
+    /*
+     * unidiff stops reading the hunk as soon as the line counts from
+     * the header are satisfied, so the marker following the very last line
+     * never makes it into the hunk - look it up in the diff text itself.
+     * diff_line_no is 1-based, so it is the index of the next line.
+     */
+    if let Some(last) = hunk.lines().last() {
+        let next_line = diff_lines.get(last.diff_line_no);
+        if last.line_type != "\\" && next_line.is_some_and(|l| l.starts_with('\\')) {
+            markers.mark_after(last);
+        }
+    }
+    markers
+}
+
+pub fn get_truncated_file_name(fname: &str, p: usize) -> String {
+    let path = std::path::Path::new(&fname);
+    let mut comp = path.components();
+    // I can't do this: let path = path.components().skip(p).as_path();
+    // So I will do this:
+    for _ in 0..p {
+        comp.next();
+    }
+    let path = comp.as_path();
+    path.to_str().unwrap().to_string()
+}
+
+/*
+ * Like GNU patch and "git apply", refuse the file names which lead
+ * out of the tree being patched: the diffs may come from anywhere.
+ */
+fn safe_path(path: String) -> Result<String, PatchError> {
+    let inside = Path::new(&path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if inside {
+        Ok(path)
+    } else {
+        Err(PatchError::UnsafePath { path })
+    }
+}
+
+/// A hunk of the diff
+#[derive(Clone, Debug)]
+pub struct Hunk {
+    hunk: unidiff::Hunk,
+    pub(crate) no_newline: NoNewlineMarkers,
+}
+
+impl Hunk {
+    /// The lines as they are in the hunk, without the "\ No newline" markers
+    pub fn diff_lines(&self) -> Vec<unidiff::Line> {
+        self.hunk
+            .lines()
+            .iter()
+            .filter(|l| l.is_context() || l.is_added() || l.is_removed())
+            .cloned()
+            .collect()
+    }
+}
+
+impl Deref for Hunk {
+    type Target = unidiff::Hunk;
+
+    fn deref(&self) -> &unidiff::Hunk {
+        &self.hunk
+    }
+}
+
+/*
+ * Without the diff text only the markers within the hunk can be seen,
+ * so the one after its last line is lost.
+ */
+impl From<unidiff::Hunk> for Hunk {
+    fn from(hunk: unidiff::Hunk) -> Self {
+        let no_newline = get_no_newline_markers(&hunk, &[]);
+        Hunk { hunk, no_newline }
+    }
+}
+
+/// The changes to a single file
+#[derive(Clone, Debug)]
+pub struct FilePatch {
+    pub source_file: String,
+    pub target_file: String,
+    /// the permission bits from the git "old mode" or "deleted file mode" header
+    pub old_mode: Option<u32>,
+    /// the permission bits from the git "new mode" or "new file mode" header
+    pub new_mode: Option<u32>,
+    /// the abbreviated ids of the blobs before and after, from the git "index" header
+    pub blob_ids: Option<(String, String)>,
+    /// whether the git "rename from" and "rename to" headers say the file is renamed
+    pub renamed: bool,
+    hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    pub fn hunks(&self) -> &[Hunk] {
+        &self.hunks
+    }
+
+    pub fn is_added_file(&self) -> bool {
+        self.source_file == DEV_NULL
+    }
+
+    pub fn is_removed_file(&self) -> bool {
+        self.target_file == DEV_NULL
+    }
+
+    /// The file to read, None for the files being created
+    pub fn source_path(&self, strip: usize) -> Result<Option<String>, PatchError> {
+        if self.is_added_file() {
+            Ok(None)
+        } else {
+            safe_path(get_truncated_file_name(&self.source_file, strip)).map(Some)
+        }
+    }
+
+    /// The file to write, None for the files being removed
+    pub fn target_path(&self, strip: usize) -> Result<Option<String>, PatchError> {
+        if self.is_removed_file() {
+            Ok(None)
+        } else {
+            safe_path(get_truncated_file_name(&self.target_file, strip)).map(Some)
+        }
+    }
+}
+
+/// What the git extended headers say about a file
+#[derive(Clone, Debug, Default)]
+struct GitHeaders {
+    old_mode: Option<u32>,
+    new_mode: Option<u32>,
+    blob_ids: Option<(String, String)>,
+    renamed: bool,
+}
+
+/// The start and the line count of a side of the hunk
+type HunkRange = (usize, usize);
+
+/// The "a,b" range of the hunk header
+fn hunk_range(range: &str) -> Option<HunkRange> {
+    match range.split_once(',') {
+        Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+        /* without the count, the range is of one line */
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+/// The old and the new range of the "@@ -a,b +c,d @@" line, and the text after them
+fn hunk_ranges(line: &str) -> Option<(HunkRange, HunkRange, &str)> {
+    let (ranges, rest) = line.strip_prefix("@@ -")?.split_once(" @@")?;
+    let (source, target) = ranges.split_once(" +")?;
+    Some((hunk_range(source)?, hunk_range(target)?, rest))
+}
+
+/*
+ * git writes the ranges of one line without the count, "@@ -1 +1 @@",
+ * which unidiff takes for no lines at all - so spell the counts out.
+ */
+fn with_hunk_counts(diff_str: &str) -> String {
+    diff_str
+        .split('\n')
+        .map(|line| match hunk_ranges(line) {
+            Some(((a, b), (c, d), rest)) => format!("@@ -{},{} +{},{} @@{}", a, b, c, d, rest),
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/*
+ * The git extended headers, one per "---" line,
+ * so they line up with the files unidiff finds.
+ * The lines of the hunks are skipped, as a removed
+ * line may well start with "-- ", e.g. in SQL.
+ */
+fn get_git_headers(diff_lines: &[&str]) -> Vec<GitHeaders> {
+    let mut all = vec![];
+    let mut headers = GitHeaders::default();
+    /* the lines of the hunk still to come, on each side */
+    let (mut source, mut target) = (0, 0);
+    for line in diff_lines {
+        if source > 0 || target > 0 {
+            let (old, new) = match line.chars().next() {
+                Some('-') => (1, 0),
+                Some('+') => (0, 1),
+                Some('\\') => (0, 0),
+                _ => (1, 1),
+            };
+            source = usize::saturating_sub(source, old);
+            target = usize::saturating_sub(target, new);
+            continue;
+        }
+        if let Some(((_, old), (_, new), _)) = hunk_ranges(line) {
+            (source, target) = (old, new);
+        } else if line.starts_with("diff --git ") {
+            headers = GitHeaders::default();
+        } else if let Some(m) = line
+            .strip_prefix("deleted file mode ")
+            .or_else(|| line.strip_prefix("old mode "))
+        {
+            headers.old_mode = u32::from_str_radix(m.trim(), 8).ok().map(|m| m & 0o7777);
+        } else if let Some(m) = line
+            .strip_prefix("new file mode ")
+            .or_else(|| line.strip_prefix("new mode "))
+        {
+            headers.new_mode = u32::from_str_radix(m.trim(), 8).ok().map(|m| m & 0o7777);
+        } else if line.starts_with("rename from ") {
+            headers.renamed = true;
+        } else if let Some(ids) = line.strip_prefix("index ") {
+            /* "index abc123..def456", with the mode after it if that is unchanged */
+            let ids = ids.split_whitespace().next().unwrap_or_default();
+            headers.blob_ids = ids
+                .split_once("..")
+                .map(|(from, to)| (from.to_string(), to.to_string()));
+        } else if line.starts_with("--- ") {
+            all.push(std::mem::take(&mut headers));
+        }
+    }
+    all
+}
+
+/// All the changes of a diff
+#[derive(Clone, Debug, Default)]
+pub struct PatchSet {
+    files: Vec<FilePatch>,
+}
+
+impl PatchSet {
+    pub fn parse(diff_str: &str) -> Result<PatchSet, PatchError> {
+        let diff_str = &with_hunk_counts(diff_str);
+        let diff_lines: Vec<&str> = diff_str.split('\n').collect();
+        let mut patch = unidiff::PatchSet::new();
+        patch.parse(diff_str).map_err(PatchError::Parse)?;
+        let git_headers = get_git_headers(&diff_lines);
+        let files = patch
+            .files()
+            .iter()
+            .enumerate()
+            .map(|(i, file)| {
+                let headers = git_headers.get(i).cloned().unwrap_or_default();
+                FilePatch {
+                    source_file: file.source_file.clone(),
+                    target_file: file.target_file.clone(),
+                    old_mode: headers.old_mode,
+                    new_mode: headers.new_mode,
+                    blob_ids: headers.blob_ids,
+                    renamed: headers.renamed,
+                    hunks: file
+                        .hunks()
+                        .iter()
+                        .map(|hunk| Hunk {
+                            hunk: hunk.clone(),
+                            no_newline: get_no_newline_markers(hunk, &diff_lines),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+        Ok(PatchSet { files })
+    }
+
+    pub fn files(&self) -> &[FilePatch] {
+        &self.files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn git_headers_after_removed_comment() {
+        let diff = "diff --git a/a.sql b/a.sql\nindex 1111111..2222222 100644\n--- a/a.sql\n+++ b/a.sql\n\
+                    @@ -1,2 +1,1 @@\n--- the old comment\n select 1;\n\
+                    diff --git a/b.sh b/b.sh\nold mode 100644\nnew mode 100755\nindex 3333333..4444444\n\
+                    --- a/b.sh\n+++ b/b.sh\n@@ -1 +1 @@\n-echo a\n+echo b\n";
+        let patch = PatchSet::parse(diff).unwrap();
+        let files = patch.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0].blob_ids,
+            Some(("1111111".to_string(), "2222222".to_string()))
+        );
+        assert_eq!((files[0].old_mode, files[0].new_mode), (None, None));
+        assert_eq!(
+            files[1].blob_ids,
+            Some(("3333333".to_string(), "4444444".to_string()))
+        );
+        assert_eq!(
+            (files[1].old_mode, files[1].new_mode),
+            (Some(0o644), Some(0o755))
+        );
+    }
+
+    #[test]
+    fn one_line_hunks_without_counts() {
+        let diff = "diff --git a/one.c b/one.c\nindex 1111111..2222222 100644\n--- a/one.c\n+++ b/one.c\n\
+                    @@ -1 +1 @@ f()\n-a;\n+b;\n\
+                    diff --git a/new.c b/new.c\nnew file mode 100644\nindex 0000000..3333333\n\
+                    --- /dev/null\n+++ b/new.c\n@@ -0,0 +1 @@\n+c;\n\
+                    diff --git a/gone.c b/gone.c\ndeleted file mode 100644\nindex 4444444..0000000\n\
+                    --- a/gone.c\n+++ /dev/null\n@@ -1 +0,0 @@\n-d;\n";
+        let patch = PatchSet::parse(diff).unwrap();
+        let files = patch.files();
+        assert_eq!(files.len(), 3);
+        let ranges = |file: &FilePatch| {
+            let hunk = &file.hunks()[0];
+            let lines = hunk
+                .diff_lines()
+                .iter()
+                .map(|l| l.value.clone())
+                .collect::<Vec<_>>();
+            (
+                (hunk.source_start, hunk.source_length),
+                (hunk.target_start, hunk.target_length),
+                lines,
+            )
+        };
+        assert_eq!(
+            ranges(&files[0]),
+            ((1, 1), (1, 1), vec!["a;".into(), "b;".into()])
+        );
+        assert_eq!(ranges(&files[1]), ((0, 0), (1, 1), vec!["c;".into()]));
+        assert_eq!(ranges(&files[2]), ((1, 1), (0, 0), vec!["d;".into()]));
+        assert_eq!(files[1].new_mode, Some(0o644));
+        assert_eq!(
+            files[2].blob_ids,
+            Some(("4444444".to_string(), "0000000".to_string()))
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use crate::parse::{atom2str, EditedParseStruct, EditedTextAtom, ParseStruct};
+use crate::patch::old_token_value;
+use ansi_term::Colour;
+use diffus::edit::{self, collection};
+use std::fmt::{self, Write};
+
+pub fn print_diff(diff: diffus::edit::Edit<'_, ParseStruct>) {
+    print!("{}", format_diff(diff));
+}
+
+/// The dump of the edits between the two parses, to print or log
+pub fn format_diff(diff: diffus::edit::Edit<'_, ParseStruct>) -> String {
+    let mut out = String::new();
+    write_diff(&mut out, diff).unwrap();
+    out
+}
+
+fn write_diff(out: &mut String, diff: diffus::edit::Edit<'_, ParseStruct>) -> fmt::Result {
+    match diff {
+        edit::Edit::Copy(x) => {
+            writeln!(out, "Identical parses: {:#?}", &x)?;
+        }
+        edit::Edit::Change(EditedParseStruct { atoms }) => {
+            let diff = atoms;
+            match diff {
+                edit::Edit::Copy(x) => writeln!(out, "no difference: {:?}", &x)?,
+                edit::Edit::Change(diff) => {
+                    for edit in diff {
+                        match edit {
+                            collection::Edit::Copy(elem) => writeln!(out, "copy: {:?}", elem)?,
+                            collection::Edit::Insert(elem) => writeln!(out, "insert: {:?}", elem)?,
+                            collection::Edit::Remove(elem) => writeln!(out, "remove: {:?}", elem)?,
+                            collection::Edit::Change(EditedTextAtom {
+                                token_value,
+                                token_uuid,
+                                leading_ws,
+                            }) => {
+                                writeln!(out, "changed:")?;
+                                match token_value {
+                                    edit::Edit::Copy(x) => writeln!(out, "    copy: id {:?}", &x)?,
+                                    x => {
+                                        writeln!(out, "    changed: id {:?}", &x)?;
+                                    }
+                                }
+                                writeln!(out, "    token_uuid: {:?}", &token_uuid)?;
+                                writeln!(out, "    leading_ws: {:?}", &leading_ws)?;
+                            }
+                        };
+                    }
+                }
+            };
+        }
+    }
+    Ok(())
+}
+
+pub fn print_diff_c(right: &ParseStruct, diff: diffus::edit::Edit<'_, ParseStruct>) {
+    print!("{}", format_diff_c(right, diff));
+}
+
+/// The colored text of the hunk, to print or log
+pub fn format_diff_c(right: &ParseStruct, diff: diffus::edit::Edit<'_, ParseStruct>) -> String {
+    let mut out = String::new();
+    write_diff_c(&mut out, right, diff).unwrap();
+    out
+}
+
+fn write_diff_c(
+    out: &mut String,
+    right: &ParseStruct,
+    diff: diffus::edit::Edit<'_, ParseStruct>,
+) -> fmt::Result {
+    let mut atom_index = 0;
+    match diff {
+        edit::Edit::Copy(x) => {
+            writeln!(out, "Identical parses: {:#?}", &x)?;
+        }
+        edit::Edit::Change(EditedParseStruct { atoms }) => {
+            let diff = atoms;
+            match diff {
+                edit::Edit::Copy(x) => {
+                    for xx in x {
+                        write!(out, "{}", &atom2str(xx))?;
+                    }
+                }
+                edit::Edit::Change(diff) => {
+                    for edit in diff {
+                        match edit {
+                            collection::Edit::Copy(elem) => {
+                                write!(out, "{}", &atom2str(elem))?;
+                                atom_index += 1;
+                            }
+                            collection::Edit::Insert(elem) => {
+                                write!(out, "{}", Colour::Green.paint(atom2str(elem)))?;
+                                atom_index += 1;
+                            }
+                            collection::Edit::Remove(elem) => {
+                                write!(out, "{}", Colour::Red.paint(atom2str(elem)))?;
+                            }
+                            collection::Edit::Change(EditedTextAtom { token_value, .. }) => {
+                                match token_value {
+                                    edit::Edit::Copy(x) => {
+                                        let ws = &right.atoms[atom_index].leading_ws;
+                                        write!(
+                                            out,
+                                            "{}",
+                                            Colour::Purple.paint(format!("{}{}", ws, &x))
+                                        )?;
+                                    }
+                                    edit::Edit::Change(value_edits) => {
+                                        let ws = &right.atoms[atom_index].leading_ws;
+                                        let tok = &right.atoms[atom_index].token_value;
+                                        write!(
+                                            out,
+                                            "{}{}{}",
+                                            ws,
+                                            Colour::Red.paint(old_token_value(&value_edits)),
+                                            Colour::Green.paint(tok)
+                                        )?;
+                                    }
+                                }
+                                atom_index += 1;
+                            }
+                        };
+                    }
+                }
+            };
+        }
+    }
+    writeln!(out)
+}
@@ -0,0 +1,106 @@
+/*
+ * Carrying a patch over a commit which only reformatted the code: the
+ * patch is applied to the tree it was made against, and the result is
+ * merged token by token with the reformatted tree, so the changes keep
+ * the new formatting; what comes out is an ordinary line-based diff
+ * against the reformatted tree.
+ */
+use crate::fs::{FileSystem, OverlayFileSystem};
+use crate::merge::{merge3, MergeOptions};
+use crate::patch::{Applied, AppliedFile, PatchError, Patcher};
+use crate::patchset::FilePatch;
+use crate::unified::refreshed_file_patch;
+
+/// The patch of a file, carried over to the new tree
+#[derive(Clone, Debug)]
+pub struct Rebased {
+    /// the diff against the new tree, in the format of "git diff"
+    pub diff: String,
+    /// where the old and the new trees differ in more than the whitespace
+    pub conflicts: usize,
+    /// the file as patched in the new tree
+    pub file: AppliedFile,
+}
+
+/// Apply the patch of the file to the old tree, and carry the result over to the new one
+pub fn rebase_file_patch(
+    patcher: &Patcher,
+    file: &FilePatch,
+    old_tree: &dyn FileSystem,
+    new_tree: &dyn FileSystem,
+) -> Result<Rebased, PatchError> {
+    let src_path = patcher.file_paths(file, old_tree)?.0;
+    let read = |tree: &dyn FileSystem| match &src_path {
+        Some(path) => tree.read_to_string(path).map_err(|error| PatchError::Io {
+            path: path.clone(),
+            error,
+        }),
+        None => Ok(String::new()),
+    };
+    let (old, new) = (read(old_tree)?, read(new_tree)?);
+    let applied = patcher.apply_file_patch(file, &OverlayFileSystem::new(old_tree))?;
+    let tokenizer = patcher.options().tokenizer;
+    let opts = MergeOptions {
+        tokenizer,
+        labels: [
+            Some("new tree".to_string()),
+            Some("old tree".to_string()),
+            Some("patched".to_string()),
+        ],
+        ..Default::default()
+    };
+    let merged = merge3(&old, &new, &applied.applied.text, &opts);
+    let file_rebased = AppliedFile {
+        applied: Applied {
+            text: merged.text,
+            ..applied.applied
+        },
+        ..applied
+    };
+    Ok(Rebased {
+        diff: refreshed_file_patch(
+            &new,
+            &file_rebased,
+            file.old_mode,
+            file.new_mode,
+            3,
+            tokenizer,
+        ),
+        conflicts: merged.conflicts,
+        file: file_rebased,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::MemoryFileSystem;
+    use crate::patch::PatchOptions;
+    use crate::patchset::PatchSet;
+
+    #[test]
+    fn rebase_reformatted() {
+        let old_tree = MemoryFileSystem::new();
+        old_tree.insert(
+            "f.c",
+            "int f(int a)\n{\n  if (a)\n    return 1;\n  return 0;\n}\n",
+        );
+        let new_tree = MemoryFileSystem::new();
+        new_tree.insert(
+            "f.c",
+            "int f(int a) {\n\tif (a)\n\t\treturn 1;\n\treturn 0;\n}\n",
+        );
+        let diff = "--- a/f.c\n+++ b/f.c\n@@ -3,4 +3,5 @@\n   if (a)\n     return 1;\n\
+                    +  log(a);\n   return 0;\n }\n";
+        let patch = PatchSet::parse(diff).unwrap();
+        let patcher = Patcher::new(PatchOptions::default());
+        let rebased = rebase_file_patch(&patcher, &patch.files()[0], &old_tree, &new_tree).unwrap();
+        assert_eq!(rebased.conflicts, 0);
+        assert_eq!(
+            rebased.diff,
+            "diff --git a/f.c b/f.c\n--- a/f.c\n+++ b/f.c\n@@ -1,5 +1,6 @@\n int f(int a) {\n \
+             \tif (a)\n \t\treturn 1;\n+\tlog(a);\n \treturn 0;\n }\n"
+        );
+        assert!(!old_tree.get("f.c").unwrap().contents.contains("log"));
+    }
+}
@@ -0,0 +1,158 @@
+/*
+ * The summary of a patch run, for the tools consuming the results.
+ */
+use crate::patch::{AppliedFile, AppliedHunk, FileOperation, HunkStatus, PatchError};
+use crate::patchset::{FilePatch, Hunk};
+use serde::Serialize;
+
+/// The lines a hunk covers on one side of the diff
+#[derive(Clone, Debug, Serialize)]
+pub struct LineSpan {
+    pub start: usize,
+    pub length: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HunkReport {
+    /// counting from 1
+    pub hunk: usize,
+    pub status: &'static str,
+    pub source: LineSpan,
+    pub target: LineSpan,
+    /// the atoms matched in the text the hunk was applied to, end exclusive;
+    /// empty, at where the conflict went, for a conflict with none of them matched
+    pub tokens: Option<[usize; 2]>,
+    /// the lines of the matched atoms, end inclusive
+    pub lines: Option<[usize; 2]>,
+    pub offset: isize,
+    pub similarity: f64,
+    /// the atoms of the result whose whitespace is not the one in the hunk
+    pub ws_adjusted: usize,
+}
+
+impl HunkReport {
+    pub fn new(index: usize, hunk: &Hunk, applied: &AppliedHunk) -> Self {
+        /* the conflicts are where the markers are */
+        let matched = applied.status.is_applied() || applied.status == HunkStatus::Conflict;
+        HunkReport {
+            hunk: index + 1,
+            status: applied.status.as_str(),
+            source: LineSpan {
+                start: hunk.source_start,
+                length: hunk.source_length,
+            },
+            target: LineSpan {
+                start: hunk.target_start,
+                length: hunk.target_length,
+            },
+            tokens: Some([applied.token_pos, applied.token_end]).filter(|_| matched),
+            lines: Some([applied.line, applied.end_line]).filter(|_| matched),
+            offset: applied.offset,
+            similarity: applied.similarity,
+            ws_adjusted: applied.ws_adjusted,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FileReport {
+    pub source_file: String,
+    pub target_file: String,
+    /// "modified", "created", "removed" or "renamed", None if the file was left alone
+    pub operation: Option<&'static str>,
+    pub renamed_from: Option<String>,
+    pub error: Option<String>,
+    pub hunks: Vec<HunkReport>,
+}
+
+impl FileReport {
+    pub fn new(file: &FilePatch, result: &Result<AppliedFile, PatchError>) -> Self {
+        let hunk_reports = |applied: &[AppliedHunk]| {
+            file.hunks()
+                .iter()
+                .zip(applied)
+                .enumerate()
+                .map(|(i, (hunk, applied))| HunkReport::new(i, hunk, applied))
+                .collect()
+        };
+        let mut report = FileReport {
+            source_file: file.source_file.clone(),
+            target_file: file.target_file.clone(),
+            operation: None,
+            renamed_from: None,
+            error: None,
+            hunks: vec![],
+        };
+        match result {
+            Ok(applied) => {
+                report.operation = Some(match &applied.operation {
+                    FileOperation::Modified => "modified",
+                    FileOperation::Created => "created",
+                    FileOperation::Removed => "removed",
+                    FileOperation::Renamed { from } => {
+                        report.renamed_from = Some(from.clone());
+                        "renamed"
+                    }
+                });
+                report.hunks = hunk_reports(&applied.applied.hunks);
+            }
+            Err(PatchError::HunksFailed { applied, .. }) => {
+                report.error = result.as_ref().err().map(|e| e.to_string());
+                report.hunks = hunk_reports(&applied.hunks);
+            }
+            Err(e) => {
+                report.error = Some(e.to_string());
+                let rejected: Vec<AppliedHunk> = file
+                    .hunks()
+                    .iter()
+                    .map(|hunk| AppliedHunk::unapplied(HunkStatus::Rejected, hunk.source_start))
+                    .collect();
+                report.hunks = hunk_reports(&rejected);
+            }
+        }
+        report
+    }
+}
+
+/// The results for all the files of a patch set
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Report {
+    pub files: Vec<FileReport>,
+}
+
+impl Report {
+    pub fn push(&mut self, file: &FilePatch, result: &Result<AppliedFile, PatchError>) {
+        self.files.push(FileReport::new(file, result));
+    }
+
+    /// "failed" or "conflicted" if any file or hunk was, "fuzzed" if any hunk was not
+    /// applied as it is, or else "clean"
+    pub fn outcome(&self) -> &'static str {
+        let hunks = || self.files.iter().flat_map(|file| &file.hunks);
+        if self.files.iter().any(|file| file.error.is_some()) {
+            "failed"
+        } else if hunks().any(|hunk| hunk.status == "conflict") {
+            "conflicted"
+        } else if hunks().any(|hunk| hunk.status != "applied") {
+            "fuzzed"
+        } else {
+            "clean"
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// What became of a commit carried over to another branch
+#[derive(Clone, Debug, Serialize)]
+pub struct CommitReport {
+    pub commit: String,
+    pub subject: String,
+    /// as Report::outcome
+    pub outcome: &'static str,
+    /// None if it was not committed
+    pub new_commit: Option<String>,
+    pub files: Vec<FileReport>,
+}
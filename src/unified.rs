@@ -0,0 +1,504 @@
+/*
+ * Rendering the token-level differences as a line-based unified diff.
+ *
+ * The lines touched by the tokens which are not copied as they are,
+ * whitespace included, are changed; the rest pair up through the copied
+ * tokens, and become the context.
+ */
+use crate::parse::{parse_string_with, EditedParseStruct, ParseStruct, Tokenizer};
+use crate::patch::{AppliedFile, FileOperation};
+use crate::patchset::DEV_NULL;
+use diffus::edit::{self, collection};
+use diffus::Diffable;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LineOp {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+fn newlines(ws: &str) -> usize {
+    ws.matches('\n').count()
+}
+
+/*
+ * The whitespace of an atom starts on the line of the previous token,
+ * which is only touched if there is anything before the newline ending it;
+ * for the first atom, there is no such token and the line is its own.
+ */
+fn first_line_touched(line: usize, ws: &str, first_atom: bool) -> usize {
+    if ws.starts_with('\n') && !first_atom {
+        line + 1
+    } else {
+        line
+    }
+}
+
+/// Mark the lines the atom (whitespace and token) starting on the line is on
+fn mark(changed: &mut [bool], line: usize, ws: &str, first_atom: bool) -> usize {
+    let last = line + newlines(ws);
+    let first = first_line_touched(line, ws, first_atom).min(last);
+    for line in changed.iter_mut().take(last + 1).skip(first) {
+        *line = true;
+    }
+    last
+}
+
+/// Which of the lines of each side are changed
+fn changed_lines(
+    old: &ParseStruct,
+    new: &ParseStruct,
+    old_lines: &[&str],
+    new_lines: &[&str],
+) -> (Vec<bool>, Vec<bool>) {
+    /* one more for the whitespace at the end of the file */
+    let mut old_changed = vec![false; old_lines.len() + 1];
+    let mut new_changed = vec![false; new_lines.len() + 1];
+    /* the lines which must either both be changed or both not */
+    let mut pairs = vec![];
+    if let edit::Edit::Change(EditedParseStruct {
+        atoms: edit::Edit::Change(diff),
+    }) = old.diff(new)
+    {
+        let (mut oi, mut ni) = (0, 0);
+        let (mut ol, mut nl) = (0, 0);
+        for edit in diff {
+            match edit {
+                collection::Edit::Copy(_) => {
+                    /* the tokens compare equal with any whitespace before them */
+                    let (ws, new_ws) = (&old.atoms[oi].leading_ws, &new.atoms[ni].leading_ws);
+                    if ws == new_ws && (oi == 0) == (ni == 0) {
+                        /* the same token, and the same blank lines before it */
+                        let n = newlines(ws);
+                        for k in first_line_touched(0, ws, oi == 0).min(n)..=n {
+                            pairs.push((ol + k, nl + k));
+                        }
+                        ol += n;
+                        nl += n;
+                    } else {
+                        ol = mark(&mut old_changed, ol, ws, oi == 0);
+                        nl = mark(&mut new_changed, nl, new_ws, ni == 0);
+                    }
+                    oi += 1;
+                    ni += 1;
+                }
+                collection::Edit::Remove(_) => {
+                    ol = mark(&mut old_changed, ol, &old.atoms[oi].leading_ws, oi == 0);
+                    oi += 1;
+                }
+                collection::Edit::Insert(_) => {
+                    nl = mark(&mut new_changed, nl, &new.atoms[ni].leading_ws, ni == 0);
+                    ni += 1;
+                }
+                collection::Edit::Change(_) => {
+                    ol = mark(&mut old_changed, ol, &old.atoms[oi].leading_ws, oi == 0);
+                    oi += 1;
+                    nl = mark(&mut new_changed, nl, &new.atoms[ni].leading_ws, ni == 0);
+                    ni += 1;
+                }
+            }
+        }
+    }
+    loop {
+        let mut more = false;
+        for &(o, n) in &pairs {
+            /* a line break may be gone and another put in its place, or not */
+            let differ = old_lines.get(o) != new_lines.get(n);
+            if old_changed[o] != new_changed[n] || (differ && !old_changed[o]) {
+                old_changed[o] = true;
+                new_changed[n] = true;
+                more = true;
+            }
+        }
+        if !more {
+            break;
+        }
+    }
+    old_changed.truncate(old_lines.len());
+    new_changed.truncate(new_lines.len());
+    (old_changed, new_changed)
+}
+
+fn line_ops(old_changed: &[bool], new_changed: &[bool]) -> Vec<LineOp> {
+    /* the context lines pair up one to one, or the diff would not apply as it should */
+    let unchanged = |changed: &[bool]| changed.iter().filter(|c| !**c).count();
+    assert_eq!(unchanged(old_changed), unchanged(new_changed));
+    let mut ops = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old_changed.len() || j < new_changed.len() {
+        let old_left = i < old_changed.len();
+        let new_left = j < new_changed.len();
+        if old_left && (old_changed[i] || !new_left) {
+            ops.push(LineOp::Delete(i));
+            i += 1;
+        } else if new_left && (new_changed[j] || !old_left) {
+            ops.push(LineOp::Insert(j));
+            j += 1;
+        } else {
+            ops.push(LineOp::Equal(i, j));
+            i += 1;
+            j += 1;
+        }
+    }
+    ops
+}
+
+fn push_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+/// The start of the hunk in the header, which for no lines is the line before it
+fn hunk_start(before: usize, count: usize) -> usize {
+    if count == 0 {
+        before
+    } else {
+        before + 1
+    }
+}
+
+/// The unified diff between the two texts, empty if they are the same
+pub fn unified_diff(
+    old: &str,
+    new: &str,
+    old_name: &str,
+    new_name: &str,
+    context: usize,
+    tokenizer: Tokenizer,
+) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let (old_changed, new_changed) = changed_lines(
+        &parse_string_with(old, tokenizer),
+        &parse_string_with(new, tokenizer),
+        &old_lines,
+        &new_lines,
+    );
+    let ops = line_ops(&old_changed, &new_changed);
+    let changes: Vec<usize> = (0..ops.len())
+        .filter(|&i| !matches!(ops[i], LineOp::Equal(..)))
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+    /* the ranges of the ops in each hunk, the changes closer than twice the context merged */
+    let mut groups: Vec<(usize, usize)> = vec![];
+    for &i in &changes {
+        match groups.last_mut() {
+            Some((_, end)) if i <= *end + 2 * context + 1 => *end = i,
+            _ => groups.push((i, i)),
+        }
+    }
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for (first, last) in groups {
+        let start = first.saturating_sub(context);
+        let end = (last + context + 1).min(ops.len());
+        let hunk_ops = &ops[start..end];
+        let old_before = ops[..start]
+            .iter()
+            .filter(|op| !matches!(op, LineOp::Insert(_)))
+            .count();
+        let new_before = ops[..start]
+            .iter()
+            .filter(|op| !matches!(op, LineOp::Delete(_)))
+            .count();
+        let old_count = hunk_ops
+            .iter()
+            .filter(|op| !matches!(op, LineOp::Insert(_)))
+            .count();
+        let new_count = hunk_ops
+            .iter()
+            .filter(|op| !matches!(op, LineOp::Delete(_)))
+            .count();
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk_start(old_before, old_count),
+            old_count,
+            hunk_start(new_before, new_count),
+            new_count
+        ));
+        for op in hunk_ops {
+            match *op {
+                LineOp::Equal(i, _) => push_line(&mut out, ' ', old_lines[i]),
+                LineOp::Delete(i) => push_line(&mut out, '-', old_lines[i]),
+                LineOp::Insert(j) => push_line(&mut out, '+', new_lines[j]),
+            }
+        }
+    }
+    out
+}
+
+/*
+ * The diff of what was done to the file, to apply with the line-based tools:
+ * with the git headers for the files created, removed or renamed, which
+ * both "git apply" and GNU patch understand.
+ */
+pub fn refreshed_file_patch(
+    original: &str,
+    applied: &AppliedFile,
+    old_mode: Option<u32>,
+    new_mode: Option<u32>,
+    context: usize,
+    tokenizer: Tokenizer,
+) -> String {
+    let path = &applied.path;
+    let (src, mut header) = match &applied.operation {
+        FileOperation::Renamed { from } => (
+            from.clone(),
+            format!("rename from {}\nrename to {}\n", from, path),
+        ),
+        _ => (path.clone(), String::new()),
+    };
+    let (old_name, new_name, new) = match &applied.operation {
+        FileOperation::Created => {
+            header = format!(
+                "new file mode {:06o}\n",
+                0o100000 | new_mode.unwrap_or(0o644)
+            );
+            (
+                DEV_NULL.to_string(),
+                format!("b/{}", path),
+                &applied.applied.text[..],
+            )
+        }
+        FileOperation::Removed => {
+            /* without the mode line git takes "/dev/null" for the name of the file */
+            header = format!(
+                "deleted file mode {:06o}\n",
+                0o100000 | old_mode.unwrap_or(0o644)
+            );
+            (format!("a/{}", path), DEV_NULL.to_string(), "")
+        }
+        _ => {
+            /* git wants the mode the file had, too */
+            if let Some(mode) = new_mode.filter(|mode| Some(*mode) != old_mode) {
+                header.push_str(&format!(
+                    "old mode {:06o}\nnew mode {:06o}\n",
+                    0o100000 | old_mode.unwrap_or(0o644),
+                    0o100000 | mode
+                ));
+            }
+            (
+                format!("a/{}", src),
+                format!("b/{}", path),
+                &applied.applied.text[..],
+            )
+        }
+    };
+    let diff = unified_diff(original, new, &old_name, &new_name, context, tokenizer);
+    if diff.is_empty() && header.is_empty() {
+        return String::new();
+    }
+    format!("diff --git a/{} b/{}\n{}{}", src, path, header, diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::Applied;
+
+    fn diff(old: &str, new: &str, context: usize) -> String {
+        unified_diff(old, new, "a/f", "b/f", context, Tokenizer::Code)
+    }
+
+    #[test]
+    fn unified_same() {
+        assert_eq!(diff("int a;\n", "int a;\n", 3), "");
+    }
+
+    #[test]
+    fn unified_change() {
+        let old = "a;\nb;\nc;\nd;\ne;\n";
+        let new = "a;\nb;\nx;\nd;\ne;\n";
+        assert_eq!(
+            diff(old, new, 1),
+            "--- a/f\n+++ b/f\n@@ -2,3 +2,3 @@\n b;\n-c;\n+x;\n d;\n"
+        );
+    }
+
+    #[test]
+    fn unified_whitespace_and_insert() {
+        let old = "a;\nb;\n";
+        let new = "a;\n  b;\nc;\n";
+        assert_eq!(
+            diff(old, new, 3),
+            "--- a/f\n+++ b/f\n@@ -1,2 +1,3 @@\n a;\n-b;\n+  b;\n+c;\n"
+        );
+    }
+
+    #[test]
+    fn unified_no_newline() {
+        assert_eq!(
+            diff("a;\nb;", "a;\nb;\n", 3),
+            "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n a;\n-b;\n\\ No newline at end of file\n+b;\n"
+        );
+    }
+
+    #[test]
+    fn unified_blank_lines() {
+        assert_eq!(
+            diff("a;\n", "\na;\n", 3),
+            "--- a/f\n+++ b/f\n@@ -1,1 +1,2 @@\n-a;\n+\n+a;\n"
+        );
+        assert_eq!(
+            diff("a;\nb;\n  \n", "a;\nb;\n", 3),
+            "--- a/f\n+++ b/f\n@@ -1,3 +1,2 @@\n a;\n b;\n-  \n"
+        );
+    }
+
+    /// The diff applied line by line, with the context and the removed lines checked
+    fn patch(old: &str, diff: &str) -> String {
+        let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+        let mut out = String::new();
+        let mut i = 0;
+        let mut lines = diff.split_inclusive('\n').skip(2).peekable();
+        while let Some(line) = lines.next() {
+            if let Some(ranges) = line.strip_prefix("@@ -") {
+                let (start, count) = ranges.split(' ').next().unwrap().split_once(',').unwrap();
+                let start: usize = start.parse().unwrap();
+                let start = if count == "0" { start } else { start - 1 };
+                out.extend(old_lines[i..start].iter().copied());
+                i = start;
+                continue;
+            }
+            /* the marker goes with the line before it */
+            let no_newline = lines.next_if(|next| next.starts_with('\\')).is_some();
+            let (prefix, text) = line.split_at(1);
+            let text = if no_newline {
+                text.trim_end_matches('\n')
+            } else {
+                text
+            };
+            if prefix != "+" {
+                assert_eq!(old_lines[i], text, "{}", diff);
+                i += 1;
+            }
+            if prefix != "-" {
+                out.push_str(text);
+            }
+        }
+        out.extend(old_lines[i..].iter().copied());
+        out
+    }
+
+    #[test]
+    fn unified_round_trip() {
+        let texts = [
+            "",
+            "a;\n",
+            "\na;\n",
+            "a;\n\n",
+            "a;\n  \n",
+            "\ta;\n\nb;\n",
+            "a;\nb;",
+            "  \n\n{\n  f(a, b);\n}\n",
+            "{\nf(a,\n  b);\n}\n\n",
+        ];
+        for old in texts {
+            for new in texts {
+                for context in [0, 1, 3] {
+                    assert_eq!(
+                        patch(old, &diff(old, new, context)),
+                        new,
+                        "{:?} {:?}",
+                        old,
+                        new
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn refreshed_rename() {
+        let applied = AppliedFile {
+            path: "new.c".to_string(),
+            operation: FileOperation::Renamed {
+                from: "old.c".to_string(),
+            },
+            applied: Applied {
+                text: "a;\nx;\n".to_string(),
+                hunks: vec![],
+            },
+        };
+        assert_eq!(
+            refreshed_file_patch("a;\nb;\n", &applied, None, None, 3, Tokenizer::Code),
+            "diff --git a/old.c b/new.c\nrename from old.c\nrename to new.c\n\
+             --- a/old.c\n+++ b/new.c\n@@ -1,2 +1,2 @@\n a;\n-b;\n+x;\n"
+        );
+    }
+
+    #[test]
+    fn refreshed_mode_change() {
+        let applied = AppliedFile {
+            path: "run.sh".to_string(),
+            operation: FileOperation::Modified,
+            applied: Applied {
+                text: "echo b;\n".to_string(),
+                hunks: vec![],
+            },
+        };
+        let diff = refreshed_file_patch(
+            "echo a;\n",
+            &applied,
+            Some(0o644),
+            Some(0o755),
+            3,
+            Tokenizer::Code,
+        );
+        assert_eq!(
+            diff,
+            "diff --git a/run.sh b/run.sh\nold mode 100644\nnew mode 100755\n\
+             --- a/run.sh\n+++ b/run.sh\n@@ -1,1 +1,1 @@\n-echo a;\n+echo b;\n"
+        );
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("run.sh"), "echo a;\n").unwrap();
+        std::fs::write(dir.path().join("fix.diff"), diff).unwrap();
+        let git = |args: &[&str]| {
+            std::process::Command::new("git")
+                .args(args)
+                .current_dir(dir.path())
+                .output()
+                .unwrap()
+        };
+        git(&["init", "-q"]);
+        git(&["add", "run.sh"]);
+        let check = git(&["apply", "--check", "--index", "fix.diff"]);
+        assert!(check.status.success(), "{:?}", check);
+        assert!(check.stderr.is_empty(), "{:?}", check);
+        assert!(git(&["apply", "--index", "fix.diff"]).status.success());
+        let staged = git(&["ls-files", "--stage", "run.sh"]).stdout;
+        assert!(staged.starts_with(b"100755 "), "{:?}", staged);
+    }
+
+    #[test]
+    fn refreshed_removal() {
+        let applied = AppliedFile {
+            path: "run.sh".to_string(),
+            operation: FileOperation::Removed,
+            applied: Applied {
+                text: String::new(),
+                hunks: vec![],
+            },
+        };
+        let removal = "--- a/run.sh\n+++ /dev/null\n@@ -1,1 +0,0 @@\n-echo;\n";
+        assert_eq!(
+            refreshed_file_patch("echo;\n", &applied, Some(0o755), None, 3, Tokenizer::Code),
+            format!(
+                "diff --git a/run.sh b/run.sh\ndeleted file mode 100755\n{}",
+                removal
+            )
+        );
+        assert_eq!(
+            refreshed_file_patch("echo;\n", &applied, None, None, 3, Tokenizer::Code),
+            format!(
+                "diff --git a/run.sh b/run.sh\ndeleted file mode 100644\n{}",
+                removal
+            )
+        );
+    }
+}
@@ -0,0 +1,462 @@
+/*
+ * Showing the differences between two texts token by token, in the manner
+ * of "git diff --word-diff".
+ */
+use crate::parse::{parse_string_with, EditedParseStruct, ParseStruct, TextAtom, Tokenizer};
+use ansi_term::Colour;
+use diffus::edit::{self, collection};
+use diffus::Diffable;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WordDiffMode {
+    /// the removed text in [-...-], the added in {+...+}
+    Plain,
+    /// the removed text in red, the added in green, without the brackets
+    Color,
+    /// for the scripts: a line for each piece, prefixed with " ", "-" or "+",
+    /// and "~" for the ends of the lines
+    Porcelain,
+}
+
+impl std::str::FromStr for WordDiffMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(WordDiffMode::Plain),
+            "color" => Ok(WordDiffMode::Color),
+            "porcelain" => Ok(WordDiffMode::Porcelain),
+            _ => Err(format!(
+                "unknown word diff mode '{}', expected plain, color or porcelain",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WordDiffOptions {
+    pub mode: WordDiffMode,
+    /// color the plain mode too
+    pub color: bool,
+    /// put the old and the new line numbers in front of the lines
+    pub line_numbers: bool,
+    /// how many unchanged lines to show around the changed ones
+    pub context: usize,
+    pub tokenizer: Tokenizer,
+    /// show the tokens only respaced as unchanged, with their new whitespace
+    pub ignore_ws: bool,
+}
+
+impl Default for WordDiffOptions {
+    fn default() -> Self {
+        WordDiffOptions {
+            mode: WordDiffMode::Plain,
+            color: false,
+            line_numbers: false,
+            context: 3,
+            tokenizer: Tokenizer::Code,
+            ignore_ws: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Common,
+    Removed,
+    Added,
+}
+
+/// A line of the output, with the line numbers it starts at
+struct OutLine {
+    old_line: usize,
+    new_line: usize,
+    pieces: Vec<(Kind, String)>,
+    /// whether it ends with a newline of the texts
+    newline: bool,
+}
+
+impl OutLine {
+    fn changed(&self) -> bool {
+        self.pieces.iter().any(|(kind, _)| *kind != Kind::Common)
+    }
+}
+
+fn atom_text(atom: &TextAtom) -> String {
+    format!("{}{}", atom.leading_ws, atom.token_value)
+}
+
+/// The common whitespace at the start of both is shown as unchanged
+fn push_change(segments: &mut Vec<(Kind, String)>, removed: &mut String, added: &mut String) {
+    let common_len = removed
+        .char_indices()
+        .zip(added.chars())
+        .take_while(|((_, r), a)| r == a && r.is_whitespace())
+        .last()
+        .map_or(0, |((i, r), _)| i + r.len_utf8());
+    if common_len > 0 {
+        segments.push((Kind::Common, removed[..common_len].to_string()));
+    }
+    if removed.len() > common_len {
+        segments.push((Kind::Removed, removed[common_len..].to_string()));
+    }
+    if added.len() > common_len {
+        segments.push((Kind::Added, added[common_len..].to_string()));
+    }
+    removed.clear();
+    added.clear();
+}
+
+fn segments(old: &ParseStruct, new: &ParseStruct, ignore_ws: bool) -> Vec<(Kind, String)> {
+    let mut segments = vec![];
+    let (mut removed, mut added) = (String::new(), String::new());
+    let diff = match old.diff(new) {
+        edit::Edit::Change(EditedParseStruct {
+            atoms: edit::Edit::Change(diff),
+        }) => diff,
+        _ => {
+            return old
+                .atoms
+                .iter()
+                .map(|atom| (Kind::Common, atom_text(atom)))
+                .collect()
+        }
+    };
+    let (mut oi, mut ni) = (0, 0);
+    for edit in diff {
+        match edit {
+            collection::Edit::Copy(_) => {
+                push_change(&mut segments, &mut removed, &mut added);
+                segments.push((Kind::Common, atom_text(&old.atoms[oi])));
+                oi += 1;
+                ni += 1;
+            }
+            collection::Edit::Remove(_) => {
+                removed.push_str(&atom_text(&old.atoms[oi]));
+                oi += 1;
+            }
+            collection::Edit::Insert(_) => {
+                added.push_str(&atom_text(&new.atoms[ni]));
+                ni += 1;
+            }
+            collection::Edit::Change(_) => {
+                let (old_atom, new_atom) = (&old.atoms[oi], &new.atoms[ni]);
+                if old_atom.token_value == new_atom.token_value && ignore_ws {
+                    push_change(&mut segments, &mut removed, &mut added);
+                    segments.push((Kind::Common, atom_text(new_atom)));
+                } else if old_atom.token_value == new_atom.token_value {
+                    /* only the whitespace changed */
+                    removed.push_str(&old_atom.leading_ws);
+                    added.push_str(&new_atom.leading_ws);
+                    push_change(&mut segments, &mut removed, &mut added);
+                    segments.push((Kind::Common, new_atom.token_value.clone()));
+                } else {
+                    removed.push_str(&atom_text(old_atom));
+                    added.push_str(&atom_text(new_atom));
+                }
+                oi += 1;
+                ni += 1;
+            }
+        }
+    }
+    push_change(&mut segments, &mut removed, &mut added);
+    segments
+}
+
+/// The lines, and the old and the new line numbers just past the last of them
+fn split_lines(segments: Vec<(Kind, String)>) -> (Vec<OutLine>, (usize, usize)) {
+    let new_line = |old_line, new_line| OutLine {
+        old_line,
+        new_line,
+        pieces: vec![],
+        newline: false,
+    };
+    let mut lines = vec![];
+    let mut line = new_line(1, 1);
+    let (mut old_line, mut new_line_no) = (1, 1);
+    for (kind, text) in segments {
+        let mut parts = text.split('\n').peekable();
+        while let Some(part) = parts.next() {
+            match line.pieces.last_mut() {
+                _ if part.is_empty() => {}
+                Some((last_kind, last)) if *last_kind == kind => last.push_str(part),
+                _ => line.pieces.push((kind, part.to_string())),
+            }
+            if parts.peek().is_some() {
+                if kind != Kind::Added {
+                    old_line += 1;
+                }
+                if kind != Kind::Removed {
+                    new_line_no += 1;
+                }
+                line.newline = true;
+                lines.push(std::mem::replace(
+                    &mut line,
+                    new_line(old_line, new_line_no),
+                ));
+            }
+        }
+    }
+    if !line.pieces.is_empty() {
+        /* the last line without a newline still counts on the sides it is on */
+        if line.pieces.iter().any(|(kind, _)| *kind != Kind::Added) {
+            old_line += 1;
+        }
+        if line.pieces.iter().any(|(kind, _)| *kind != Kind::Removed) {
+            new_line_no += 1;
+        }
+        lines.push(line);
+    }
+    (lines, (old_line, new_line_no))
+}
+
+fn render_line(out: &mut String, line: &OutLine, opts: &WordDiffOptions, width: usize) {
+    if opts.mode == WordDiffMode::Porcelain {
+        if opts.line_numbers {
+            out.push_str(&format!("#{} {}\n", line.old_line, line.new_line));
+        }
+        for (kind, text) in &line.pieces {
+            let prefix = match kind {
+                Kind::Common => ' ',
+                Kind::Removed => '-',
+                Kind::Added => '+',
+            };
+            out.push_str(&format!("{}{}\n", prefix, text));
+        }
+        if line.newline {
+            out.push_str("~\n");
+        }
+        return;
+    }
+    if opts.line_numbers {
+        let gutter = format!("{:>w$} {:>w$} | ", line.old_line, line.new_line, w = width);
+        if opts.color {
+            out.push_str(&Colour::Fixed(244).paint(gutter).to_string());
+        } else {
+            out.push_str(&gutter);
+        }
+    }
+    for (kind, text) in &line.pieces {
+        let (open, close, colour) = match kind {
+            Kind::Common => {
+                out.push_str(text);
+                continue;
+            }
+            Kind::Removed => ("[-", "-]", Colour::Red),
+            Kind::Added => ("{+", "+}", Colour::Green),
+        };
+        match (opts.mode, opts.color) {
+            (WordDiffMode::Color, _) => out.push_str(&colour.paint(text.as_str()).to_string()),
+            (_, true) => out.push_str(
+                &colour
+                    .paint(format!("{}{}{}", open, text, close))
+                    .to_string(),
+            ),
+            (_, false) => out.push_str(&format!("{}{}{}", open, text, close)),
+        }
+    }
+    out.push('\n');
+}
+
+/// How many tokens are changed between the texts, and how many only respaced
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WordDiffStats {
+    pub removed: usize,
+    pub added: usize,
+    pub respaced: usize,
+}
+
+impl WordDiffStats {
+    pub fn new(old: &str, new: &str, tokenizer: Tokenizer) -> Self {
+        let (old, new) = (
+            parse_string_with(old, tokenizer),
+            parse_string_with(new, tokenizer),
+        );
+        let mut stats = WordDiffStats::default();
+        if let edit::Edit::Change(EditedParseStruct {
+            atoms: edit::Edit::Change(diff),
+        }) = old.diff(&new)
+        {
+            let (mut oi, mut ni) = (0, 0);
+            for edit in diff {
+                match edit {
+                    collection::Edit::Copy(_) => {
+                        oi += 1;
+                        ni += 1;
+                    }
+                    collection::Edit::Remove(_) => {
+                        stats.removed += 1;
+                        oi += 1;
+                    }
+                    collection::Edit::Insert(_) => {
+                        stats.added += 1;
+                        ni += 1;
+                    }
+                    collection::Edit::Change(_) => {
+                        if old.atoms[oi].token_value == new.atoms[ni].token_value {
+                            stats.respaced += 1;
+                        } else {
+                            stats.removed += 1;
+                            stats.added += 1;
+                        }
+                        oi += 1;
+                        ni += 1;
+                    }
+                }
+            }
+        }
+        stats
+    }
+}
+
+impl std::fmt::Display for WordDiffStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.removed + self.added, self.respaced) {
+            (0, 0) => write!(f, "no changes"),
+            (0, respaced) => write!(f, "whitespace only, {} tokens respaced", respaced),
+            (_, respaced) => write!(
+                f,
+                "{} tokens removed, {} added, {} respaced",
+                self.removed, self.added, respaced
+            ),
+        }
+    }
+}
+
+/// All the lines of the parses with the changes marked, without the hunk headers
+pub(crate) fn mark_changes(old: &ParseStruct, new: &ParseStruct, opts: &WordDiffOptions) -> String {
+    let mut out = String::new();
+    for line in &split_lines(segments(old, new, opts.ignore_ws)).0 {
+        render_line(&mut out, line, opts, 1);
+    }
+    out
+}
+
+/// The changed lines of the texts with the changes marked, empty if there are none
+pub fn word_diff(old: &str, new: &str, opts: &WordDiffOptions) -> String {
+    let opts = WordDiffOptions {
+        color: opts.color || opts.mode == WordDiffMode::Color,
+        ..*opts
+    };
+    let (lines, end_of_texts) = split_lines(segments(
+        &parse_string_with(old, opts.tokenizer),
+        &parse_string_with(new, opts.tokenizer),
+        opts.ignore_ws,
+    ));
+    let width = lines
+        .last()
+        .map_or(1, |line| line.old_line.max(line.new_line).to_string().len());
+    /* the lines to show, the changed ones merged when the context between them overlaps */
+    let mut groups: Vec<(usize, usize)> = vec![];
+    for (i, _) in lines.iter().enumerate().filter(|(_, line)| line.changed()) {
+        let start = i.saturating_sub(opts.context);
+        let end = (i + opts.context + 1).min(lines.len());
+        match groups.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => groups.push((start, end)),
+        }
+    }
+    let mut out = String::new();
+    for (start, end) in groups {
+        let (old_end, new_end) = lines
+            .get(end)
+            .map_or(end_of_texts, |line| (line.old_line, line.new_line));
+        let (old_start, new_start) = (lines[start].old_line, lines[start].new_line);
+        /* like in the unified diff, an empty side starts at the line before */
+        let header = format!(
+            "@@ -{},{} +{},{} @@",
+            old_start - usize::from(old_end == old_start),
+            old_end - old_start,
+            new_start - usize::from(new_end == new_start),
+            new_end - new_start
+        );
+        if opts.color {
+            out.push_str(&Colour::Cyan.paint(header).to_string());
+        } else {
+            out.push_str(&header);
+        }
+        out.push('\n');
+        for line in &lines[start..end] {
+            render_line(&mut out, line, &opts, width);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(old: &str, new: &str, mode: WordDiffMode, line_numbers: bool) -> String {
+        let opts = WordDiffOptions {
+            mode,
+            line_numbers,
+            context: 1,
+            ..Default::default()
+        };
+        word_diff(old, new, &opts)
+    }
+
+    #[test]
+    fn word_diff_same() {
+        assert_eq!(diff("a = 1;\n", "a = 1;\n", WordDiffMode::Plain, false), "");
+    }
+
+    #[test]
+    fn word_diff_plain() {
+        let old = "a = 1;\nb;\nc;\nd;\n";
+        let new = "a = 2;\nb;\nc;\nd;\n";
+        assert_eq!(
+            diff(old, new, WordDiffMode::Plain, false),
+            "@@ -1,2 +1,2 @@\na = [-1-]{+2+};\nb;\n"
+        );
+        assert_eq!(
+            diff(old, new, WordDiffMode::Plain, true),
+            "@@ -1,2 +1,2 @@\n1 1 | a = [-1-]{+2+};\n2 2 | b;\n"
+        );
+        /* the last lines, without the newline at the end */
+        assert_eq!(
+            diff("a;\nb;\nc", "a;\nb;\nd", WordDiffMode::Plain, false),
+            "@@ -2,2 +2,2 @@\nb;\n[-c-]{+d+}\n"
+        );
+    }
+
+    #[test]
+    fn word_diff_porcelain() {
+        assert_eq!(
+            diff("f(a);\n", "f(a, b);\n", WordDiffMode::Porcelain, false),
+            "@@ -1,1 +1,1 @@\n f(a\n+, b\n );\n~\n"
+        );
+        assert_eq!(
+            diff(
+                "a;\nf(a);\n",
+                "a;\nf(a, b);\n",
+                WordDiffMode::Porcelain,
+                true
+            ),
+            "@@ -1,2 +1,2 @@\n#1 1\n a;\n~\n#2 2\n f(a\n+, b\n );\n~\n"
+        );
+    }
+
+    #[test]
+    fn word_diff_ignore_ws() {
+        let (old, new) = ("if (a)\n\tf(1);\n", "if (a)\n    f(2);\n");
+        let opts = WordDiffOptions {
+            ignore_ws: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            word_diff(old, new, &opts),
+            "@@ -1,2 +1,2 @@\nif (a)\n    f([-1-]{+2+});\n"
+        );
+        assert_eq!(
+            WordDiffStats::new(old, new, Tokenizer::Code).to_string(),
+            "1 tokens removed, 1 added, 1 respaced"
+        );
+        assert_eq!(
+            WordDiffStats::new(old, "if (a)\n  f(1);\n", Tokenizer::Code).to_string(),
+            "whitespace only, 1 tokens respaced"
+        );
+    }
+}
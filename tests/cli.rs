@@ -0,0 +1,187 @@
+/*
+ * Running the tbpatch binary, for what only shows from the outside:
+ * the exit status, and what goes to stdout and to stderr.
+ */
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn tbpatch(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tbpatch"))
+        .args(args)
+        .current_dir(dir)
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap()
+}
+
+fn write(dir: &Path, fname: &str, contents: &str) {
+    std::fs::write(dir.join(fname), contents).unwrap();
+}
+
+fn read(dir: &Path, fname: &str) -> String {
+    std::fs::read_to_string(dir.join(fname)).unwrap()
+}
+
+fn merge_driver(ours: &str, theirs: &str, marker_size: &str) -> (Output, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    write(dir, "base", "f(a, b);\ng(c);\n");
+    write(dir, "ours", ours);
+    write(dir, "theirs", theirs);
+    let args = ["merge-driver", "base", "ours", "theirs", marker_size, "f.c"];
+    let output = tbpatch(dir, &args);
+    (output, read(dir, "ours"))
+}
+
+#[test]
+fn merge_driver_clean() {
+    let (output, merged) = merge_driver("f(a, b);\ng(x);\n", "f(a,\n  b);\ng(c);\n", "7");
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(merged, "f(a,\n  b);\ng(x);\n");
+}
+
+#[test]
+fn merge_driver_conflict() {
+    let (output, merged) = merge_driver("f(a, b);\ng(x);\n", "f(a, b);\ng(y);\n", "10");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(merged.contains("<<<<<<<<<< ours:f.c\n"), "{}", merged);
+    assert!(merged.contains("\n==========\n"), "{}", merged);
+    assert!(merged.contains(">>>>>>>>>> theirs:f.c\n"), "{}", merged);
+    assert!(!merged.contains("<<<<<<<<<<<"), "{}", merged);
+}
+
+fn patch_dir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    write(dir.path(), "f.c", "int a;\nint b;\n");
+    write(
+        dir.path(),
+        "fix.diff",
+        "--- a/f.c\n+++ b/f.c\n@@ -1,2 +1,2 @@\n int a;\n-int b;\n+int x;\n",
+    );
+    dir
+}
+
+#[test]
+fn logging_levels() {
+    let stderr = |args: &[&str]| {
+        let dir = patch_dir();
+        let output = tbpatch(dir.path(), args);
+        assert!(output.status.success());
+        /* the messages never get into the output proper */
+        assert!(output.stdout.is_empty(), "{:?}", output);
+        assert_eq!(read(dir.path(), "f.c"), "int a;\nint x;\n");
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let info = stderr(&["fix.diff"]);
+    assert_eq!(info, "patching file f.c\n");
+    assert_eq!(stderr(&["-q", "fix.diff"]), "");
+    let debug = stderr(&["-v", "fix.diff"]);
+    assert!(debug.contains("patching file f.c\n") && debug.contains("hunk #1: found at"));
+    assert!(!debug.contains("hunk #1 edits:"));
+    let trace = stderr(&["-vv", "fix.diff"]);
+    assert!(trace.contains("hunk #1 edits:"));
+}
+
+#[test]
+fn logging_errors_when_quiet() {
+    let dir = patch_dir();
+    write(dir.path(), "f.c", "int q;\n");
+    let output = tbpatch(dir.path(), &["-q", "fix.diff"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("error: Hunk #1 FAILED at 1."), "{}", stderr);
+    assert!(!stderr.contains("patching file"), "{}", stderr);
+}
+
+#[test]
+fn am_refuses_local_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+    };
+    git(&["init", "-q"]);
+    git(&["config", "user.name", "Committer"]);
+    git(&["config", "user.email", "c@example.com"]);
+    write(dir, "f.c", "a;\n");
+    git(&["add", "f.c"]);
+    git(&["commit", "-q", "-m", "Base"]);
+    write(
+        dir,
+        "fix.mbox",
+        "From 1234567890abcdef Mon Sep 17 00:00:00 2001\nFrom: Bob <bob@example.com>\n\
+         Subject: [PATCH] Fix\n\n---\ndiff --git a/f.c b/f.c\n--- a/f.c\n+++ b/f.c\n\
+         @@ -1 +1 @@\n-a;\n+b;\n",
+    );
+    write(dir, "f.c", "a;\nlocal;\n");
+    let output = tbpatch(dir, &["am", "fix.mbox"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("commit or stash them first"), "{}", stderr);
+    assert_eq!(read(dir, "f.c"), "a;\nlocal;\n");
+    assert!(!dir.join(".git/tbpatch-am").exists());
+
+    /* once they are gone, the mail applies */
+    write(dir, "f.c", "a;\n");
+    let output = tbpatch(dir, &["am", "fix.mbox"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(read(dir, "f.c"), "b;\n");
+}
+
+#[test]
+fn config_turned_off_on_command_line() {
+    let run = |text: &str, args: &[&str]| {
+        let dir = patch_dir();
+        write(
+            dir.path(),
+            ".tbpatch.toml",
+            "backup = true\nmerge = \"diff3\"\nws_only_edits = true\n",
+        );
+        write(dir.path(), "f.c", text);
+        let output = tbpatch(dir.path(), args);
+        (
+            output,
+            read(dir.path(), "f.c"),
+            dir.path().join("f.c.orig").exists(),
+        )
+    };
+    let (output, patched, backup) = run("int a;\nint q;\n", &["fix.diff"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(patched.contains("|||||||"), "{}", patched);
+    assert!(backup);
+    let (output, patched, backup) = run(
+        "int a;\nint q;\n",
+        &["--merge=none", "--no-backup", "fix.diff"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(patched, "int a;\nint q;\n");
+    assert!(!backup);
+    /* the last one given wins */
+    let (_, patched, backup) = run("int a;\nint b;\n", &["--no-backup", "-b", "fix.diff"]);
+    assert_eq!(patched, "int a;\nint x;\n");
+    assert!(backup);
+
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    write(dir, ".tbpatch.toml", "ws_only_edits = true\n");
+    write(
+        dir,
+        "ws.diff",
+        "--- a/f.c\n+++ b/f.c\n@@ -1,2 +1,2 @@\n int a;\n-int b;\n+  int b;\n",
+    );
+    write(dir, "f.c", "int a;\nint b;\n");
+    assert!(tbpatch(dir, &["ws.diff"]).status.success());
+    assert_eq!(read(dir, "f.c"), "int a;\n  int b;\n");
+    write(dir, "f.c", "int a;\nint b;\n");
+    assert!(tbpatch(dir, &["--no-ws-only-edits", "ws.diff"])
+        .status
+        .success());
+    assert_eq!(read(dir, "f.c"), "int a;\nint b;\n");
+}